@@ -1,26 +1,197 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, path::PathBuf};
 use crate::errors::OperationError;
+use crate::storage::{block::BlockKind, block_stroage::{BlockSeek, BlockStorage, OpenMode, ReaderError, StorageConfig, StorageOption, WriterError}, index::Index, serialization::{FromBytes, ToBytes}};
+
+// Block storage behind a persistent collection
+struct CollectionStorage {
+    storage: BlockStorage,
+    index: Index,
+}
+
+impl fmt::Debug for CollectionStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CollectionStorage")
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CollectionStorage {
+    // Reads the index of an open storage, a new file gets an empty one laid down first
+    fn load(storage: BlockStorage) -> Result<Self, OperationError> {
+        let mut header = storage.read_header()?;
+
+        let Some(index_root) = header.index_root else {
+            // New file, lay down an empty index and point the header at it
+            let mut index = Index::new();
+            storage.begin()?;
+            let BlockSeek::Start(index_root) = storage.write_item(BlockKind::Index, 0, &index.to_bytes_vec())? else {
+                unreachable!("write_item always returns an absolute position");
+            };
+            index.set_chain(vec![index_root]);
+            header.index_root = Some(index_root);
+            storage.write_header(&header)?;
+            storage.commit()?;
+            return Ok(Self { storage, index });
+        };
+
+        if !storage.read_block(BlockSeek::Start(index_root))?.is_index() {
+            return Err(ReaderError::FromReaderError(format!("Block {index_root} is not an index block")).into());
+        }
+        let mut index = Index::from_bytes_vec(&storage.read_full_item(BlockSeek::Start(index_root))?)?;
+        index.set_chain(storage.chain_positions(index_root)?);
+        Ok(Self { storage, index })
+    }
+
+    fn save_index(&mut self) -> Result<(), OperationError> {
+        let chain = self.storage.rewrite_item(BlockKind::Index, 0, &self.index.to_bytes_vec(), self.index.get_chain())?;
+        self.index.set_chain(chain);
+        Ok(())
+    }
+
+    // Runs `operation` as one transaction, after a crash either all of its blocks are on disk or none are
+    fn atomically(&mut self, operation: impl FnOnce(&mut Self) -> Result<(), OperationError>) -> Result<(), OperationError> {
+        let index = self.index.clone();
+        self.storage.begin()?;
+        let result = operation(self).and_then(|_| Ok(self.storage.commit()?));
+        if let Err(err) = result {
+            self.index = index;
+            return match self.storage.rollback() {
+                Ok(()) => Err(err),
+                Err(rollback) => Err(OperationError::RollbackFailed { cause: Box::new(err), rollback }),
+            };
+        }
+        Ok(())
+    }
+
+    fn put_document(&mut self, id: u64, value: &String) -> Result<(), OperationError> {
+        let head = self.storage.write_document(id, value.as_bytes())?;
+        self.index.insert(id, head);
+        self.save_index()
+    }
+
+    fn write_document(&mut self, id: u64, value: &String) -> Result<(), OperationError> {
+        self.atomically(|storage| storage.put_document(id, value))
+    }
+
+    fn update_document(&mut self, id: u64, value: &String) -> Result<(), OperationError> {
+        self.atomically(|storage| {
+            // the old version goes first, a page holds one version of a document
+            if let Some(head) = storage.index.get(id) {
+                storage.storage.release_document(id, head)?;
+            }
+            storage.put_document(id, value)
+        })
+    }
+
+    // Writes the live documents one after another into a fresh storage
+    fn write_compacted(&self, documents: &HashMap<u64, String>, stored_in: StorageOption) -> Result<(), OperationError> {
+        let config = self.storage.get_config();
+        let mut compacted = Self::load(BlockStorage::open_with_config(stored_in, OpenMode::CreateNew, config)?)?;
+
+        // nobody sees the copy before it's swapped in, so it's written without a transaction and synced once
+        let mut ids: Vec<u64> = documents.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let head = compacted.storage.write_document(id, documents[&id].as_bytes())?;
+            compacted.index.insert(id, head);
+        }
+        compacted.index.set_next_id(self.index.get_next_id());
+        compacted.save_index()?;
+        compacted.storage.sync()?;
+        Ok(())
+    }
+
+    fn delete_document(&mut self, id: u64) -> Result<(), OperationError> {
+        self.atomically(|storage| {
+            let Some(head) = storage.index.remove(id) else {
+                return Ok(());
+            };
+            storage.save_index()?;
+            storage.storage.release_document(id, head)?;
+            Ok(())
+        })
+    }
+}
+
+// What a compaction did to the size of the file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompactionReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl CompactionReport {
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct Collection {
     documents: HashMap<u64, String>,
-    next_id: u64
+    next_id: u64,
+    storage: Option<CollectionStorage>,
+    closed: bool, // the storage was lost, changes are refused rather than kept in memory only
 }
 
-#[allow(dead_code)]
 impl Collection {
     pub fn new() -> Self{
         Self {
             documents: HashMap::new(),
-            next_id: 1
+            next_id: 1,
+            storage: None,
+            closed: false,
+        }
+    }
+
+    pub fn open(path: PathBuf) -> Result<Self, OperationError> {
+        Self::open_with_mode(path, OpenMode::OpenOrCreate)
+    }
+
+    pub fn open_with_mode(path: PathBuf, mode: OpenMode) -> Result<Self, OperationError> {
+        Self::open_storage(StorageOption::File(path), mode)
+    }
+
+    pub fn open_storage(stored_in: StorageOption, mode: OpenMode) -> Result<Self, OperationError> {
+        Self::open_storage_with_config(stored_in, mode, StorageConfig::default())
+    }
+
+    pub fn open_storage_with_config(stored_in: StorageOption, mode: OpenMode, config: StorageConfig) -> Result<Self, OperationError> {
+        let storage = CollectionStorage::load(BlockStorage::open_with_config(stored_in, mode, config)?)?;
+
+        // Only the blocks the index points at are read, free and bookkeeping blocks are never touched
+        let mut documents = HashMap::new();
+        for (id, head) in storage.index.iter() {
+            let data = storage.storage.read_document(id, head)?;
+            documents.insert(id, String::from_bytes_vec(&data)?);
+        }
+
+        Ok(Self {
+            documents,
+            next_id: storage.index.get_next_id(),
+            storage: Some(storage),
+            closed: false,
+        })
+    }
+
+    // The storage changes go through, None for a collection kept in memory only
+    fn storage_mut(&mut self) -> Result<Option<&mut CollectionStorage>, OperationError> {
+        if self.closed {
+            return Err(OperationError::StorageClosed);
         }
+        Ok(self.storage.as_mut())
     }
 
     pub fn write(&mut self, value: String) -> Result<u64, OperationError> {
-        let ret_val = self.next_id.clone();
-        self.documents.insert(self.next_id, value);
-        self.next_id += 1;
+        let ret_val = self.next_id;
+        // the counter is read from the file, and the id after it has to fit as well
+        let next_id = ret_val.checked_add(1).ok_or(OperationError::IdsExhausted)?;
+        if let Some(storage) = self.storage_mut()? {
+            storage.write_document(ret_val, &value)?;
+        }
+        self.documents.insert(ret_val, value);
+        self.next_id = next_id;
 
         Ok(ret_val)
     }
@@ -30,14 +201,29 @@ impl Collection {
     }
 
     pub fn update(&mut self, key: u64, new_value: &String) -> Result<u64, OperationError>{
+        if !self.documents.contains_key(&key) {
+            return Err(OperationError::KeyMissing);
+        }
+        if let Some(storage) = self.storage_mut()? {
+            storage.update_document(key, new_value)?;
+        }
         let value_wrapped = self.documents.get_mut(&key); 
         match value_wrapped {
-            Some(value) => {*value = String::from(new_value); Ok(key)},
+            Some(value) => {
+                *value = String::from(new_value);
+                Ok(key)
+            },
             None => Err(OperationError::KeyMissing)
         }
     }
 
     pub fn delete(&mut self, key: u64) -> Result<String, OperationError> {
+        if !self.documents.contains_key(&key) {
+            return Err(OperationError::KeyMissing);
+        }
+        if let Some(storage) = self.storage_mut()? {
+            storage.delete_document(key)?;
+        }
         let value_wrapped = self.documents.remove(&key);
         match value_wrapped {
             Some(value) => {Ok(value)},
@@ -45,6 +231,50 @@ impl Collection {
         }
     }
 
+    // Rewrites the live documents contiguously into a new file, with a fresh index and an empty
+    // free space list, then swaps it in for the old one
+    pub fn compact(&mut self) -> Result<CompactionReport, OperationError> {
+        if self.closed {
+            return Err(OperationError::StorageClosed);
+        }
+        let Some(storage) = self.storage.as_ref() else {
+            return Ok(CompactionReport::default());
+        };
+        if storage.storage.get_mode() == OpenMode::ReadOnly {
+            return Err(WriterError::ReadOnly.into());
+        }
+        storage.storage.flush()?;
+        let stored_in = storage.storage.get_stored_in().clone();
+        let config = storage.storage.get_config();
+        let bytes_before = storage.storage.file_size()?;
+
+        // until the swap a failure leaves the old file as it was
+        let scratch = stored_in.scratch().map_err(WriterError::from)?;
+        storage.write_compacted(&self.documents, scratch.clone())?;
+        // the old file's log goes with it, opening the new one must not replay it there
+        storage.storage.sync()?;
+
+        // the old handles go before the file under them is replaced, but the lock is kept for the new
+        // ones, so no one else opens the file in between. A failed swap may leave either file in place,
+        // so the index is read back from whichever one opens
+        let lock = self.storage.take().and_then(|storage| storage.storage.into_lock());
+        let replaced = stored_in.replace_with(&scratch);
+        let reopened = BlockStorage::open_with_lock(stored_in, OpenMode::OpenExisting, config, lock)
+            .map_err(OperationError::from)
+            .and_then(CollectionStorage::load);
+        match reopened {
+            Ok(storage) => self.storage = Some(storage),
+            Err(err) => {
+                self.closed = true;
+                return Err(err);
+            },
+        }
+        replaced.map_err(WriterError::from)?;
+
+        let bytes_after = self.storage.as_ref().expect("reopened above").storage.file_size()?;
+        Ok(CompactionReport { bytes_before, bytes_after })
+    }
+
     // Helper functions for testing
     pub fn len(&self) -> usize {
         self.documents.len()
@@ -58,6 +288,7 @@ impl Collection {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::{block::DEFAULT_DATA_SIZE, block_stroage::Reader, header::{HeaderError, HEADER_SIZE}, memory::MemoryBuffer, slotted::SlottedPage, verify::verify_storage, wal::{Wal, WalEntry}};
 
     #[allow(unused_must_use)]
     fn setup_db() -> Collection {
@@ -119,6 +350,16 @@ mod tests {
         assert_eq!(pre_del, deleted_string);
     }
 
+    #[test]
+    fn test_ids_run_out() {
+        let mut collection = setup_db();
+        collection.next_id = u64::MAX - 1;
+        assert_eq!(collection.write(String::from("last")).unwrap(), u64::MAX - 1);
+        assert!(matches!(collection.write(String::from("one too many")), Err(OperationError::IdsExhausted)));
+        assert_eq!(collection.len(), 2);
+        assert_eq!(collection.get_next_id(), u64::MAX);
+    }
+
     #[test]
     fn test_read_unexisting() {
         let collection = setup_db();
@@ -145,4 +386,449 @@ mod tests {
         let result = collection.update(99, &String::from("Yo yo yo"));
         assert!(matches!(result, Err(OperationError::KeyMissing)))
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_open_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let collection = Collection::open(dir.path().join("collection.db")).unwrap();
+        assert_eq!(collection.len(), 0);
+        assert_eq!(collection.get_next_id(), 1);
+    }
+
+    #[test]
+    fn test_persist_between_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+        let long_value = "x".repeat(DEFAULT_DATA_SIZE * 2 + 17);
+
+        let mut collection = Collection::open(path.clone()).unwrap();
+        assert_eq!(collection.write(String::from("Hello123")).unwrap(), 1);
+        assert_eq!(collection.write(long_value.clone()).unwrap(), 2);
+        assert_eq!(collection.write(String::from("Bye")).unwrap(), 3);
+        drop(collection);
+
+        let collection = Collection::open(path).unwrap();
+        assert_eq!(collection.len(), 3);
+        assert_eq!(collection.get_next_id(), 4);
+        assert_eq!(collection.read(1).unwrap(), Some(&String::from("Hello123")));
+        assert_eq!(collection.read(2).unwrap(), Some(&long_value));
+        assert_eq!(collection.read(3).unwrap(), Some(&String::from("Bye")));
+    }
+
+    #[test]
+    fn test_persist_update_and_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+
+        let mut collection = Collection::open(path.clone()).unwrap();
+        collection.write(String::from("Hello123")).unwrap();
+        collection.write(String::from("Hello456")).unwrap();
+        collection.update(1, &"y".repeat(DEFAULT_DATA_SIZE + 1)).unwrap();
+        collection.delete(2).unwrap();
+        drop(collection);
+
+        let mut collection = Collection::open(path).unwrap();
+        assert_eq!(collection.len(), 1);
+        assert_eq!(collection.read(1).unwrap(), Some(&"y".repeat(DEFAULT_DATA_SIZE + 1)));
+        assert!(collection.read(2).unwrap().is_none());
+        assert!(matches!(collection.delete(2), Err(OperationError::KeyMissing)));
+        assert!(matches!(collection.update(2, &String::from("Yo")), Err(OperationError::KeyMissing)));
+    }
+
+    #[test]
+    fn test_deleted_blocks_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+
+        let mut collection = Collection::open(path.clone()).unwrap();
+        collection.write("a".repeat(DEFAULT_DATA_SIZE * 2)).unwrap();
+        collection.write(String::from("keep me")).unwrap();
+        collection.delete(1).unwrap();
+        let size_after_delete = std::fs::metadata(&path).unwrap().len();
+        drop(collection);
+
+        // the free space list survives reopening
+        let mut collection = Collection::open(path.clone()).unwrap();
+        let free_blocks = collection.storage.as_ref().unwrap().storage.free_block_count().unwrap();
+        assert_eq!(free_blocks, 3);
+
+        collection.write("b".repeat(DEFAULT_DATA_SIZE)).unwrap();
+        collection.write(String::from("small")).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size_after_delete);
+        drop(collection);
+
+        let collection = Collection::open(path).unwrap();
+        assert_eq!(collection.len(), 3);
+        assert_eq!(collection.get_next_id(), 5);
+        assert_eq!(collection.read(2).unwrap(), Some(&String::from("keep me")));
+        assert_eq!(collection.read(3).unwrap(), Some(&"b".repeat(DEFAULT_DATA_SIZE)));
+        assert_eq!(collection.read(4).unwrap(), Some(&String::from("small")));
+    }
+
+    #[test]
+    fn test_shrunk_index_frees_its_blocks() {
+        let buffer = MemoryBuffer::new();
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew).unwrap();
+        for _ in 0..200 {
+            collection.write("x".repeat(DEFAULT_DATA_SIZE / 2)).unwrap();
+        }
+        let index_blocks = collection.storage.as_ref().unwrap().index.get_chain().len();
+        assert!(index_blocks > 1);
+
+        for id in 1..=195 {
+            collection.delete(id).unwrap();
+        }
+        let storage = collection.storage.as_ref().unwrap();
+        assert_eq!(storage.index.get_chain().len(), 1);
+        assert_eq!(storage.storage.free_block_count().unwrap(), 195 + index_blocks - 1);
+        drop(collection);
+
+        let verified = verify_storage(StorageOption::Memory(buffer)).unwrap();
+        assert!(verified.is_ok(), "{:?}", verified.problems);
+    }
+
+    #[test]
+    fn test_index_is_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+
+        let mut collection = Collection::open(path.clone()).unwrap();
+        for i in 0..200 {
+            collection.write(format!("document {i}")).unwrap();
+        }
+        collection.delete(200).unwrap();
+        collection.update(5, &String::from("updated")).unwrap();
+        drop(collection);
+
+        let reader = Reader::new(StorageOption::File(path.clone())).unwrap();
+        let index_root = reader.read_header().unwrap().index_root.unwrap();
+        let index = Index::from_bytes_vec(&reader.read_full_item(BlockSeek::Start(index_root)).unwrap()).unwrap();
+        assert_eq!(index.len(), 199);
+        assert_eq!(index.get_next_id(), 201);
+        assert!(index.get(200).is_none());
+        assert!(reader.chain_positions(index_root).unwrap().len() > 1);
+        let head = index.get(5).unwrap();
+        let page = SlottedPage::from_bytes_vec(&reader.read_block(BlockSeek::Start(head)).unwrap().data).unwrap();
+        assert_eq!(page.get(5), Some(&b"updated"[..]));
+
+        // the highest id was deleted, but it must not be handed out again
+        let mut collection = Collection::open(path).unwrap();
+        assert_eq!(collection.len(), 199);
+        assert_eq!(collection.write(String::from("new")).unwrap(), 201);
+        assert_eq!(collection.read(5).unwrap(), Some(&String::from("updated")));
+    }
+
+    #[test]
+    fn test_open_foreign_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+        std::fs::write(&path, vec![7u8; 4096]).unwrap();
+
+        let result = Collection::open(path);
+        assert!(matches!(result, Err(OperationError::WriterError(WriterError::HeaderError(HeaderError::ForeignFile)))));
+    }
+
+    #[test]
+    fn test_open_newer_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+        Collection::open(path.clone()).unwrap();
+
+        let mut header = Reader::new(StorageOption::File(path.clone())).unwrap().read_header().unwrap();
+        header.version += 1;
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[..HEADER_SIZE].copy_from_slice(&header.to_bytes_vec());
+        std::fs::write(&path, bytes).unwrap();
+
+        let result = Collection::open(path);
+        assert!(matches!(
+            result,
+            Err(OperationError::WriterError(WriterError::HeaderError(HeaderError::UnsupportedVersion { .. })))
+        ));
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+
+        let result = Collection::open_with_mode(path.clone(), OpenMode::ReadOnly);
+        assert!(result.is_err());
+
+        let mut collection = Collection::open_with_mode(path.clone(), OpenMode::CreateNew).unwrap();
+        collection.write(String::from("Hello123")).unwrap();
+        drop(collection);
+
+        let mut collection = Collection::open_with_mode(path, OpenMode::ReadOnly).unwrap();
+        assert_eq!(collection.read(1).unwrap(), Some(&String::from("Hello123")));
+
+        let result = collection.write(String::from("nope"));
+        assert!(matches!(result, Err(OperationError::WriterError(WriterError::ReadOnly))));
+        let result = collection.update(1, &String::from("nope"));
+        assert!(matches!(result, Err(OperationError::WriterError(WriterError::ReadOnly))));
+        let result = collection.delete(1);
+        assert!(matches!(result, Err(OperationError::WriterError(WriterError::ReadOnly))));
+
+        // nothing changed in memory either
+        assert_eq!(collection.len(), 1);
+        assert_eq!(collection.get_next_id(), 2);
+        assert_eq!(collection.read(1).unwrap(), Some(&String::from("Hello123")));
+    }
+
+    #[test]
+    fn test_in_memory_storage() {
+        let buffer = MemoryBuffer::new();
+
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew).unwrap();
+        collection.write(String::from("Hello123")).unwrap();
+        collection.write("z".repeat(DEFAULT_DATA_SIZE * 3)).unwrap();
+        collection.delete(1).unwrap();
+        drop(collection);
+
+        let collection = Collection::open_storage(StorageOption::Memory(buffer), OpenMode::OpenExisting).unwrap();
+        assert_eq!(collection.len(), 1);
+        assert_eq!(collection.read(2).unwrap(), Some(&"z".repeat(DEFAULT_DATA_SIZE * 3)));
+        assert_eq!(collection.get_next_id(), 3);
+    }
+
+    // Leaves the file as a crash would, the write of a second document logged but only half applied
+    fn crash_mid_write(path: &PathBuf) -> Vec<u8> {
+        let mut collection = Collection::open(path.clone()).unwrap();
+        collection.write(String::from("Hello123")).unwrap();
+        let before = std::fs::read(path).unwrap();
+        collection.write("w".repeat(DEFAULT_DATA_SIZE * 3)).unwrap();
+        let after = std::fs::read(path).unwrap();
+        drop(collection);
+
+        Wal::open(&Wal::path_for(path)).unwrap().log(&[WalEntry { offset: 0, bytes: after.clone() }]).unwrap();
+        let mut half_applied = after[..before.len() + DEFAULT_DATA_SIZE].to_vec();
+        half_applied[..HEADER_SIZE].copy_from_slice(&before[..HEADER_SIZE]);
+        std::fs::write(path, half_applied).unwrap();
+        after
+    }
+
+    #[test]
+    fn test_replay_log_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+        let after = crash_mid_write(&path);
+
+        let result = Collection::open_with_mode(path.clone(), OpenMode::ReadOnly);
+        assert!(matches!(result, Err(OperationError::ReaderError(ReaderError::FromReaderError(_)))));
+
+        let collection = Collection::open(path.clone()).unwrap();
+        assert_eq!(collection.len(), 2);
+        assert_eq!(collection.read(2).unwrap(), Some(&"w".repeat(DEFAULT_DATA_SIZE * 3)));
+        assert_eq!(std::fs::read(&path).unwrap(), after);
+        drop(collection);
+
+        // replayed once, the log is empty again
+        assert!(Wal::open(&Wal::path_for(&path)).unwrap().committed().unwrap().is_empty());
+        let collection = Collection::open_with_mode(path, OpenMode::ReadOnly).unwrap();
+        assert_eq!(collection.len(), 2);
+    }
+
+    #[test]
+    fn test_torn_log_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+        let first = dir.path().join("first.db");
+
+        let mut collection = Collection::open(first.clone()).unwrap();
+        collection.write(String::from("Hello123")).unwrap();
+        drop(collection);
+        std::fs::copy(&first, &path).unwrap();
+
+        // the crash hit while the log itself was being written, so the blocks were never touched
+        Wal::open(&Wal::path_for(&path)).unwrap().log(&[WalEntry { offset: HEADER_SIZE as u64, bytes: vec![9u8; 64] }]).unwrap();
+        let wal_path = Wal::path_for(&path);
+        let log = std::fs::read(&wal_path).unwrap();
+        std::fs::write(&wal_path, &log[..log.len() - 10]).unwrap();
+
+        let collection = Collection::open(path.clone()).unwrap();
+        assert_eq!(collection.len(), 1);
+        assert_eq!(collection.read(1).unwrap(), Some(&String::from("Hello123")));
+        assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(&first).unwrap());
+    }
+
+    #[test]
+    fn test_block_size_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small.db");
+        let large = dir.path().join("large.db");
+        let value = "s".repeat(3000);
+
+        for (path, block_size) in [(&small, 256), (&large, 16 * 1024)] {
+            let config = StorageConfig { block_size, ..StorageConfig::default() };
+            let mut collection = Collection::open_storage_with_config(StorageOption::File(path.clone()), OpenMode::CreateNew, config).unwrap();
+            collection.write(value.clone()).unwrap();
+            collection.write(String::from("tiny")).unwrap();
+        }
+
+        // a 3000 byte document spans 13 small blocks, but fits one large block
+        let reader = Reader::new(StorageOption::File(small.clone())).unwrap();
+        assert_eq!(reader.get_block_size(), 256);
+        assert_eq!(reader.read_header().unwrap().block_size, 256);
+        let reader = Reader::new(StorageOption::File(large.clone())).unwrap();
+        assert_eq!(reader.get_block_size(), 16 * 1024);
+
+        // the size stored in the file wins over the one asked for when opening
+        for (path, block_size) in [(small, 256), (large, 16 * 1024)] {
+            let collection = Collection::open(path).unwrap();
+            assert_eq!(collection.storage.as_ref().unwrap().storage.get_block_size(), block_size);
+            assert_eq!(collection.read(1).unwrap(), Some(&value));
+            assert_eq!(collection.read(2).unwrap(), Some(&String::from("tiny")));
+        }
+    }
+
+    #[test]
+    fn test_small_documents_share_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+
+        let mut collection = Collection::open(path.clone()).unwrap();
+        for i in 0..1000 {
+            collection.write(format!("doc {i:05}")).unwrap();
+        }
+        // 9 bytes and a 16 byte slot each, about 40 to a block instead of one
+        let blocks = collection.storage.as_ref().unwrap().storage.block_count().unwrap();
+        assert!(blocks < 60, "{blocks} blocks for 1000 tiny documents");
+
+        collection.update(10, &String::from("grown, but still small")).unwrap();
+        collection.update(11, &"big".repeat(DEFAULT_DATA_SIZE)).unwrap();
+        collection.delete(12).unwrap();
+        drop(collection);
+
+        let mut collection = Collection::open(path.clone()).unwrap();
+        assert_eq!(collection.len(), 999);
+        assert_eq!(collection.read(1).unwrap(), Some(&String::from("doc 00000")));
+        assert_eq!(collection.read(10).unwrap(), Some(&String::from("grown, but still small")));
+        assert_eq!(collection.read(11).unwrap(), Some(&"big".repeat(DEFAULT_DATA_SIZE)));
+        assert_eq!(collection.read(1000).unwrap(), Some(&String::from("doc 00999")));
+
+        // emptying a page hands its block back
+        let storage = collection.storage.as_ref().unwrap();
+        let free_before = storage.storage.free_block_count().unwrap();
+        let head = storage.index.get(1).unwrap();
+        let shared: Vec<u64> = storage.index.iter().filter(|(_, at)| *at == head).map(|(id, _)| id).collect();
+        assert!(shared.len() > 30);
+        for id in shared {
+            collection.delete(id).unwrap();
+        }
+        let storage = &collection.storage.as_ref().unwrap().storage;
+        assert_eq!(storage.free_block_count().unwrap(), free_before + 1);
+    }
+
+    #[test]
+    fn test_page_changes_roll_back() {
+        let mut collection = Collection::open_storage(StorageOption::Memory(MemoryBuffer::new()), OpenMode::CreateNew).unwrap();
+        collection.write(String::from("first")).unwrap();
+        collection.write(String::from("second")).unwrap();
+
+        let storage = collection.storage.as_mut().unwrap();
+        let result = storage.atomically(|storage| {
+            storage.put_document(3, &String::from("third"))?;
+            storage.storage.release_document(1, storage.index.get(1).unwrap())?;
+            Err(OperationError::KeyMissing)
+        });
+        assert!(result.is_err());
+
+        // both documents are still in the page, and writes after the rollback land next to them again
+        let head = storage.index.get(1).unwrap();
+        assert_eq!(storage.storage.read_document(1, head).unwrap(), b"first");
+        assert_eq!(storage.storage.read_document(2, head).unwrap(), b"second");
+        collection.write(String::from("third")).unwrap();
+        let storage = collection.storage.as_ref().unwrap();
+        assert_eq!(storage.index.get(3), Some(head));
+        assert_eq!(storage.storage.read_document(3, head).unwrap(), b"third");
+    }
+
+    #[test]
+    fn test_pages_filled_after_reopen() {
+        let buffer = MemoryBuffer::new();
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew).unwrap();
+        collection.write(String::from("first")).unwrap();
+        let head = collection.storage.as_ref().unwrap().index.get(1).unwrap();
+        drop(collection);
+
+        // the page written before the reopen still takes small documents
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer), OpenMode::OpenExisting).unwrap();
+        collection.write(String::from("second")).unwrap();
+        let storage = collection.storage.as_ref().unwrap();
+        assert_eq!(storage.index.get(2), Some(head));
+        assert_eq!(storage.storage.read_document(2, head).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_lost_storage_refuses_changes() {
+        let mut collection = Collection::open_storage(StorageOption::Memory(MemoryBuffer::new()), OpenMode::CreateNew).unwrap();
+        collection.write(String::from("kept")).unwrap();
+
+        // what a compaction leaves behind when the file can't be opened again
+        collection.storage = None;
+        collection.closed = true;
+        assert!(matches!(collection.write(String::from("lost")), Err(OperationError::StorageClosed)));
+        assert!(matches!(collection.update(1, &String::from("lost")), Err(OperationError::StorageClosed)));
+        assert!(matches!(collection.delete(1), Err(OperationError::StorageClosed)));
+        assert!(matches!(collection.compact(), Err(OperationError::StorageClosed)));
+        assert_eq!(collection.read(1).unwrap(), Some(&String::from("kept")));
+        assert_eq!(collection.get_next_id(), 2);
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+
+        let mut collection = Collection::open(path.clone()).unwrap();
+        for i in 0..40 {
+            collection.write(format!("{i}").repeat(DEFAULT_DATA_SIZE / 2)).unwrap();
+            collection.write(format!("small {i}")).unwrap();
+        }
+        for id in (1..=80).filter(|id| id % 4 != 0) {
+            collection.delete(id).unwrap();
+        }
+        collection.update(4, &String::from("shrunk")).unwrap();
+        collection.delete(80).unwrap();
+
+        let report = collection.compact().unwrap();
+        assert!(report.bytes_reclaimed() > 0);
+        assert_eq!(report.bytes_before - report.bytes_after, report.bytes_reclaimed());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), report.bytes_after);
+        assert!(!dir.path().join("collection.db.compact").exists());
+        assert!(!dir.path().join("collection.db.compact.lock").exists());
+
+        // the swapped in file is used from here on, and still locked by this collection
+        assert!(matches!(Collection::open(path.clone()), Err(OperationError::WriterError(WriterError::InUse))));
+        let storage = &collection.storage.as_ref().unwrap().storage;
+        assert_eq!(storage.free_block_count().unwrap(), 0);
+        collection.write(String::from("after")).unwrap();
+        drop(collection);
+
+        let collection = Collection::open(path).unwrap();
+        assert_eq!(collection.len(), 20);
+        assert_eq!(collection.read(4).unwrap(), Some(&String::from("shrunk")));
+        assert_eq!(collection.read(8).unwrap(), Some(&String::from("small 3")));
+        assert_eq!(collection.read(11).unwrap(), None);
+        assert_eq!(collection.read(81).unwrap(), Some(&String::from("after")));
+        assert_eq!(collection.get_next_id(), 82); // 80 was deleted before compacting, but is still used up
+    }
+
+    #[test]
+    fn test_compact_in_memory() {
+        let buffer = MemoryBuffer::new();
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew).unwrap();
+        collection.write("z".repeat(DEFAULT_DATA_SIZE * 3)).unwrap();
+        collection.write(String::from("keep")).unwrap();
+        collection.delete(1).unwrap();
+
+        let report = collection.compact().unwrap();
+        assert!(report.bytes_reclaimed() >= 3 * DEFAULT_DATA_SIZE as u64);
+        assert_eq!(buffer.len().unwrap(), report.bytes_after);
+        drop(collection);
+
+        let collection = Collection::open_storage(StorageOption::Memory(buffer), OpenMode::OpenExisting).unwrap();
+        assert_eq!(collection.read(2).unwrap(), Some(&String::from("keep")));
+        assert_eq!(Collection::new().compact().unwrap(), CompactionReport::default());
+    }
+}
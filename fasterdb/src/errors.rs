@@ -1,4 +1,40 @@
+use crate::storage::{block_stroage::{ReaderError, StorageError, WriterError}, serialization::FromBytesError};
+
 #[derive(Debug)]
 pub enum OperationError {
-    KeyMissing
-}
\ No newline at end of file
+    KeyMissing,
+    IdsExhausted, // every id up to u64::MAX was handed out, deleted ones are never reused
+    StorageClosed, // the file couldn't be opened again after a compaction, changes would only be kept in memory
+    WriterError(WriterError),
+    ReaderError(ReaderError),
+    FromBytesError(FromBytesError),
+    RollbackFailed { cause: Box<OperationError>, rollback: WriterError }, // the file may still hold part of the transaction
+}
+
+impl From<WriterError> for OperationError {
+    fn from(err: WriterError) -> Self {
+        Self::WriterError(err)
+    }
+}
+
+impl From<ReaderError> for OperationError {
+    fn from(err: ReaderError) -> Self {
+        Self::ReaderError(err)
+    }
+}
+
+impl From<FromBytesError> for OperationError {
+    fn from(err: FromBytesError) -> Self {
+        Self::FromBytesError(err)
+    }
+}
+
+impl From<StorageError> for OperationError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::WriterError(err) => Self::WriterError(err),
+            StorageError::ReaderError(err) => Self::ReaderError(err),
+            StorageError::RollbackFailed { cause, rollback } => Self::RollbackFailed { cause: Box::new((*cause).into()), rollback },
+        }
+    }
+}
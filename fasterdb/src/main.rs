@@ -1,7 +1,70 @@
+// The binary only runs the command line tools, the rest of these modules is library API. Error
+// payloads are only ever read through Debug
+#[allow(dead_code)]
 mod errors;
+#[allow(dead_code)]
 mod collection;
+#[allow(dead_code)]
 mod storage;
 
-fn main() {
-    println!("Hello, world!");
-}
\ No newline at end of file
+use std::{path::Path, process::ExitCode};
+
+use storage::{rebuild, verify};
+
+const USAGE: &str = "usage: fasterdb verify <file> [--repair] | fasterdb rebuild-index <file>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["verify", path] => run_verify(Path::new(path), false),
+        ["verify", path, "--repair"] | ["verify", "--repair", path] => run_verify(Path::new(path), true),
+        ["rebuild-index", path] => run_rebuild(Path::new(path)),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::from(2)
+        },
+    }
+}
+
+// Prints what was found, the exit code is 0 only for a clean file or one that was repaired
+fn run_verify(path: &Path, repair: bool) -> ExitCode {
+    let result = if repair { verify::repair(path) } else { verify::verify(path) };
+    let report = match result {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("{}: {:?}", path.display(), err);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    println!("{}: {} blocks, {} documents, {} free blocks", path.display(), report.blocks, report.documents, report.free_blocks);
+    for problem in &report.problems {
+        println!("  {problem}");
+    }
+    match (report.problems.is_empty(), report.repaired) {
+        (true, _) => println!("no problems found"),
+        (false, true) => println!("{} problems repaired", report.problems.len()),
+        (false, false) if repair => println!("{} problems found, the index or free space list is too broken to repair", report.problems.len()),
+        (false, false) => println!("{} problems found, run with --repair to fix them", report.problems.len()),
+    }
+    if report.problems.is_empty() || report.repaired { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+fn run_rebuild(path: &Path) -> ExitCode {
+    let report = match rebuild::rebuild_index(path) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("{}: {:?}", path.display(), err);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    println!("{}: {} documents indexed, next id {}, {} free blocks", path.display(), report.documents, report.next_id, report.free_blocks);
+    for position in &report.skipped {
+        println!("  block {position} could not be read and was freed");
+    }
+    for id in &report.duplicates {
+        println!("  document {id} was found more than once, the first copy was kept");
+    }
+    ExitCode::SUCCESS
+}
@@ -1,32 +1,109 @@
-use crate::storage::{block_stroage::BlockSeek, serialization::{FromBytes, SizeExtraction, ToBytes}};
+use crate::storage::{block_stroage::BlockSeek, checksum::crc32c, serialization::{FromBytes, FromBytesError, SizeExtraction, ToBytes}};
+pub const KIND_SIZE:usize = 1;
+pub const FLAGS_SIZE:usize = 1;
 pub const ID_SIZE:usize = 8;
-pub const BLOCK_DATA_SIZE:usize = 1008;
-pub const NEXT_BLOCK_OFFSET_SIZE:usize = 8;
-pub const TOTAL_BLOCK_SIZE: usize = ID_SIZE + BLOCK_DATA_SIZE + NEXT_BLOCK_OFFSET_SIZE;
+pub const BLOCK_HEADER_SIZE:usize = KIND_SIZE + FLAGS_SIZE + ID_SIZE;
+pub const NEXT_BLOCK_SIZE:usize = 8;
+pub const CHECKSUM_SIZE:usize = 4;
+pub const BLOCK_OVERHEAD: usize = BLOCK_HEADER_SIZE + NEXT_BLOCK_SIZE + CHECKSUM_SIZE; // every byte of a block that isn't data
+pub const DEFAULT_BLOCK_SIZE: usize = 1024;
+pub const DEFAULT_DATA_SIZE: usize = DEFAULT_BLOCK_SIZE - BLOCK_OVERHEAD;
+pub const MIN_BLOCK_SIZE: usize = 64;
+pub const MAX_BLOCK_SIZE: usize = 1 << 20;
+const NO_NEXT_BLOCK: u64 = u64::MAX; // block 0 is a real block, so the end of a chain needs its own marker
+
+// What a block holds, stored in its first byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[repr(u8)]
+pub enum BlockKind {
+    #[default]
+    Free = 0,      // deleted or never written, stored as all zeros
+    Document = 1,  // first block of a document
+    Index = 2,     // first block of the primary index
+    FreeSpace = 3, // first block of the free space list
+    Overflow = 4,  // any later block of a chain, the kind of the chain is the kind of its first block
+    Metadata = 5,
+    Slotted = 6,   // page shared by small documents, see slotted.rs
+}
+
+impl TryFrom<u8> for BlockKind {
+    type Error = FromBytesError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Free,
+            1 => Self::Document,
+            2 => Self::Index,
+            3 => Self::FreeSpace,
+            4 => Self::Overflow,
+            5 => Self::Metadata,
+            6 => Self::Slotted,
+            _ => return Err(FromBytesError::UnknownBlockKind(value)),
+        })
+    }
+}
 
 // Block structure for file storage
 #[derive(Debug, Clone)]
 pub struct Block {
-    pub id: u64,
-    pub data: [u8; BLOCK_DATA_SIZE],
-    pub next_block_offset: u64,
+    pub kind: BlockKind,
+    pub flags: u8, // reserved for per block options, always 0 for now
+    pub id: u64,   // owning document id, 0 for blocks that don't belong to a document
+    pub data: Vec<u8>, // block size minus the overhead, every block of a file has the same size
+    pub next_block: Option<u64>, // absolute position of the next block in the chain
 }
 
 impl Default for Block {
     fn default() -> Self {
-        Self {
-            id: 0,
-            data: [0u8; BLOCK_DATA_SIZE],
-            next_block_offset: 0, // 0 means no next block
-        }
+        Self::with_size(DEFAULT_BLOCK_SIZE)
     }
 }
 
+// Sizes outside these bounds can't hold a chain link, or aren't worth a single read
+pub fn is_valid_block_size(block_size: usize) -> bool {
+    (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+}
+
 impl Block {
     pub fn new() -> Self {
         Self::default()
     }
-    
+
+    // An empty block that takes `block_size` bytes once serialized
+    pub fn with_size(block_size: usize) -> Self {
+        Self {
+            kind: BlockKind::Free,
+            flags: 0,
+            id: 0,
+            data: vec![0u8; block_size - BLOCK_OVERHEAD],
+            next_block: None,
+        }
+    }
+
+    pub fn with_kind(kind: BlockKind, id: u64) -> Self {
+        Self { kind, id, ..Self::default() }
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.data.len() + BLOCK_OVERHEAD
+    }
+
+    pub fn get_kind(&self) -> BlockKind {
+        self.kind
+    }
+
+    pub fn set_kind(&mut self, kind: BlockKind) {
+        self.kind = kind;
+    }
+
+    pub fn get_flags(&self) -> u8 {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+    }
+
     pub fn get_id(&self) -> u64 {
         self.id
     }
@@ -36,26 +113,35 @@ impl Block {
     }
 
     pub fn set_index(&mut self) {
-        self.id = u64::MAX;
+        self.kind = BlockKind::Index;
     }
-    
+
+    pub fn set_free_space(&mut self) {
+        self.kind = BlockKind::FreeSpace;
+    }
+
     pub fn set_deleted(&mut self) {
-        self.id = 0;
+        self.kind = BlockKind::Free;
     }
 
     pub fn is_deleted(&self) -> bool {
-        self.get_id() == 0
+        self.kind == BlockKind::Free
     }
-    
+
     pub fn is_index(&self) -> bool {
-        self.get_id() == u64::MAX
+        self.kind == BlockKind::Index
+    }
+
+    pub fn is_free_space(&self) -> bool {
+        self.kind == BlockKind::FreeSpace
     }
 
     pub fn set_data(&mut self, data: &[u8], offset: usize) -> Result<(), String> {
-        if offset + data.len() > BLOCK_DATA_SIZE {
+        let capacity = self.data.len();
+        if offset + data.len() > capacity {
             let data_len = data.len();
             let total_size = offset + data.len();
-            return Err(format!("Data size {data_len} + offset {offset} = {total_size} exceeds block capacity ({BLOCK_DATA_SIZE})"));
+            return Err(format!("Data size {data_len} + offset {offset} = {total_size} exceeds block capacity ({capacity})"));
         }
         
         self.data[offset..offset + data.len()].copy_from_slice(data);
@@ -63,59 +149,77 @@ impl Block {
     }
     
     pub fn get_data(&self, size: usize, offset: usize) -> Result<Vec<u8>, String> {
-        if size + offset > BLOCK_DATA_SIZE {
-            return Err(format!("Data requested, exeeds {BLOCK_DATA_SIZE}"));
+        if size + offset > self.data.len() {
+            return Err(format!("Data requested, exeeds {}", self.data.len()));
         }
         Ok(self.data[offset..size + offset].to_vec())
     }
     
-    pub fn set_next_block(&mut self, offset: u64) {
-        self.next_block_offset = offset;
+    pub fn set_next_block(&mut self, position: u64) {
+        self.next_block = Some(position);
     }
-    
-    pub fn get_next_block(&self) -> u64 {
-        self.next_block_offset
+
+    pub fn clear_next_block(&mut self) {
+        self.next_block = None;
+    }
+
+    pub fn get_next_block(&self) -> Option<u64> {
+        self.next_block
+    }
+
+    // The checksum stored in a serialized block, and the one its contents actually hash to
+    pub fn checksums(bytes: &[u8]) -> (u32, u32) {
+        let checksum_offset = bytes.len().saturating_sub(CHECKSUM_SIZE);
+        let stored = u32::from_le_bytes(bytes[checksum_offset..].try_into().unwrap_or_default());
+        (stored, crc32c(&bytes[..checksum_offset]))
     }
+
     pub fn get_next_offset(&self) -> Option<BlockSeek> {
-        let next_block = self.get_next_block();
-        match next_block {
-            0 => None,
-            _ => Some(BlockSeek::Current(next_block as i64))
-        }
+        self.get_next_block().map(BlockSeek::Start)
     }
 
 }
 
 impl ToBytes for Block {
     fn to_bytes_vec(&self) -> Vec<u8> {
-        match self.id {
-            0 => {vec![0u8;TOTAL_BLOCK_SIZE]},
+        let mut out_vec = match self.kind {
+            BlockKind::Free => {vec![0u8;self.get_size() - CHECKSUM_SIZE]},
             _ => {
-                let mut out_vec = self.get_id().to_bytes_vec();
-                out_vec.extend(self.data);
-                let fill_in_size = (BLOCK_DATA_SIZE + ID_SIZE) - out_vec.len();
-                out_vec.extend(vec![0u8;fill_in_size]);
-                out_vec.extend(self.get_next_block().to_bytes_vec());
+                let mut out_vec = vec![self.kind as u8, self.flags];
+                out_vec.extend(self.get_id().to_bytes_vec());
+                out_vec.extend(&self.data);
+                out_vec.extend(self.get_next_block().unwrap_or(NO_NEXT_BLOCK).to_bytes_vec());
                 out_vec
             }
-        }
+        };
+        out_vec.extend(crc32c(&out_vec).to_bytes_vec());
+        out_vec
     }
 }
 
 impl FromBytes for Block {
-    fn from_bytes_vec(bytes: &[u8]) -> Result<Self, super::serialization::FromBytesError> where Self:Sized {
-        if bytes[0..8].iter().all(|&b| b == 0) {
-            return Ok(Self::default());
+    fn from_bytes_vec(bytes: &[u8]) -> Result<Self, FromBytesError> where Self:Sized {
+        // the block size is whatever the caller read, the file header says how much that is
+        if !is_valid_block_size(bytes.len()) {
+            return Err(FromBytesError::ReadLenError);
         }
-        
-        let id = u64::from_bytes_vec(&bytes[0..8])?;
-        let data_array: [u8; BLOCK_DATA_SIZE] = bytes[8..1016].try_into().map_err(|_| super::serialization::FromBytesError::ReadLenError)?;
-        let next_block_offset = u64::from_bytes_vec(&bytes[1016..1024])?;
-        Ok(Self { id: id, data: data_array, next_block_offset: next_block_offset })
+        let kind = BlockKind::try_from(bytes[0])?;
+        if kind == BlockKind::Free {
+            return Ok(Self::with_size(bytes.len()));
+        }
+
+        let next_block_start = bytes.len() - CHECKSUM_SIZE - NEXT_BLOCK_SIZE;
+        let id = u64::from_bytes_vec(&bytes[KIND_SIZE + FLAGS_SIZE..BLOCK_HEADER_SIZE])?;
+        let data = bytes[BLOCK_HEADER_SIZE..next_block_start].to_vec();
+        let next_block = match u64::from_bytes_vec(&bytes[next_block_start..next_block_start + NEXT_BLOCK_SIZE])? {
+            NO_NEXT_BLOCK => None,
+            position => Some(position),
+        };
+        Ok(Self { kind, flags: bytes[KIND_SIZE], id, data, next_block })
     }
 
     fn get_size_strategy() -> SizeExtraction {
-        SizeExtraction::Constant(1024)
+        SizeExtraction::Constant(DEFAULT_BLOCK_SIZE)
     }
 }
 #[cfg(test)]
@@ -125,19 +229,21 @@ mod test{
     use super::*;
 
     #[test]
+    #[allow(clippy::unnecessary_fold, clippy::bool_assert_comparison)]
     fn test_new_block() {
         let block = Block::new();
         assert!(block.data.iter().fold(true, |v1, v2| -> bool {v1 && (v2 == &0)}), "Not all values are 0");
-        assert_eq!(block.next_block_offset, 0);
+        assert_eq!(block.next_block, None);
         assert_eq!(block.get_id(), 0);
         assert_eq!(block.is_deleted(), true);
         assert_eq!(block.is_index(), false);
+        assert_eq!(block.is_free_space(), false);
     }
 
     #[test]
     fn test_block_io_values_export_to_bytes() {
-        let mut block = Block::new();
-        block.set_id(12);
+        let mut block = Block::with_kind(BlockKind::Document, 12);
+        block.set_flags(0b10);
         let data = String::from("I am").to_bytes_vec();
         let out_res = block.set_data(&data, 10);
         block.set_next_block(1000);
@@ -148,33 +254,47 @@ mod test{
         let result = out_res.unwrap();
         assert_eq!(result.len(), 80);
         assert_eq!(block.get_id(), 12);
-        assert_eq!(block.get_next_block(), 1000);
+        assert_eq!(block.get_next_block(), Some(1000));
         assert_eq!(&result[0..7], vec![0u8;7]);
         assert_eq!(&result[7..20], vec![4, 0, 0, 0, 0, 0, 0, 0, 73, 32, 97, 109, 0]);
         assert_eq!(&result[20..], vec![0u8; 60]);
 
         let bytes_vec = block.to_bytes_vec();
         assert_eq!(bytes_vec.len(), 1024);
+        // check kind and flags
+        assert_eq!(bytes_vec[0], BlockKind::Document as u8);
+        assert_eq!(bytes_vec[1], 0b10);
+
         // check id portion
-        assert_eq!(bytes_vec[0], 12);
-        assert_eq!(bytes_vec[1..8], [0;7]); 
+        assert_eq!(bytes_vec[2], 12);
+        assert_eq!(bytes_vec[3..10], [0;7]);
 
         // test offset
-        assert_eq!(bytes_vec[8..18], [0;10]);
-        
+        assert_eq!(bytes_vec[10..20], [0;10]);
+
         // test sting length
-        assert_eq!(bytes_vec[18], 4);
-        assert_eq!(bytes_vec[19..26], [0;7]);
+        assert_eq!(bytes_vec[20], 4);
+        assert_eq!(bytes_vec[21..28], [0;7]);
 
         // test string
-        assert_eq!(bytes_vec[26..30], [73, 32, 97, 109]);
-        
+        assert_eq!(bytes_vec[28..32], [73, 32, 97, 109]);
+
         // test rest of the block
-        assert_eq!(bytes_vec[30..1016], [0;986]);
+        assert_eq!(bytes_vec[32..1012], [0;980]);
         
         // test the next item
-        assert_eq!(bytes_vec[1016..1018], [232, 3]);
-        assert_eq!(bytes_vec[1018..], [0; 6]);
+        assert_eq!(bytes_vec[1012..1014], [232, 3]);
+        assert_eq!(bytes_vec[1014..1020], [0; 6]);
+
+        // test the checksum
+        assert_eq!(bytes_vec[1020..], crc32c(&bytes_vec[..1020]).to_le_bytes());
+        assert_eq!(Block::checksums(&bytes_vec), (crc32c(&bytes_vec[..1020]), crc32c(&bytes_vec[..1020])));
+
+        let loaded = Block::from_bytes_vec(&bytes_vec).unwrap();
+        assert_eq!(loaded.get_kind(), BlockKind::Document);
+        assert_eq!(loaded.get_flags(), 0b10);
+        assert_eq!(loaded.get_id(), 12);
+        assert_eq!(loaded.data, block.data);
 
     }
 
@@ -183,27 +303,42 @@ mod test{
         let mut block = Block::new();
         block.set_next_block(11);
 
-        assert_eq!(block.next_block_offset, 11);
-        assert_eq!(block.get_next_block(), 11);
+        assert_eq!(block.next_block, Some(11));
+        assert_eq!(block.get_next_block(), Some(11));
+        assert_eq!(block.get_next_offset(), Some(BlockSeek::Start(11)));
+
+        // block 0 is a valid link target, distinct from the end of the chain
+        block.set_next_block(0);
+        assert_eq!(block.get_next_offset(), Some(BlockSeek::Start(0)));
+        block.set_kind(BlockKind::Overflow);
+        let loaded = Block::from_bytes_vec(&block.to_bytes_vec()).unwrap();
+        assert_eq!(loaded.get_next_block(), Some(0));
+
+        block.clear_next_block();
+        assert_eq!(block.get_next_offset(), None);
+        let bytes = block.to_bytes_vec();
+        assert_eq!(bytes[1012..1020], [0xFF; 8]);
+        assert_eq!(Block::from_bytes_vec(&bytes).unwrap().get_next_block(), None);
     }
 
     #[test]
+    #[allow(clippy::useless_format)]
     fn test_data_store_error() {
         let mut block = Block::new();
-        let res = block.set_data(&[11;BLOCK_DATA_SIZE], 0);
+        let res = block.set_data(&[11;DEFAULT_DATA_SIZE], 0);
         assert_eq!(res, Ok(()));
 
         let mut block = Block::new();
-        let res = block.set_data(&[11], 1009);
-        assert_eq!(res, Err(format!("Data size 1 + offset 1009 = 1010 exceeds block capacity (1008)")));
+        let res = block.set_data(&[11], 1003);
+        assert_eq!(res, Err(format!("Data size 1 + offset 1003 = 1004 exceeds block capacity (1002)")));
 
         let mut block = Block::new();
-        let res = block.set_data(&[11;1009], 0);
-        assert_eq!(res, Err("Data size 1009 + offset 0 = 1009 exceeds block capacity (1008)".to_string()));
+        let res = block.set_data(&[11;1003], 0);
+        assert_eq!(res, Err("Data size 1003 + offset 0 = 1003 exceeds block capacity (1002)".to_string()));
         
         let mut block = Block::new();
-        let res = block.set_data(&[11;1000], 9);
-        assert_eq!(res, Err("Data size 1000 + offset 9 = 1009 exceeds block capacity (1008)".to_string()));
+        let res = block.set_data(&[11;1000], 3);
+        assert_eq!(res, Err("Data size 1000 + offset 3 = 1003 exceeds block capacity (1002)".to_string()));
     }
 
     #[test]
@@ -213,14 +348,73 @@ mod test{
         block.set_id(20);
         assert_eq!(block.id, 20);
         assert_eq!(block.get_id(), 20);
-        
+
+        // the kind is independent of the id, any id is a valid document id
         block.set_index();
-        assert_eq!(block.id, u64::MAX);
-        assert_eq!(block.get_id(), u64::MAX);
-        
+        assert_eq!(block.get_kind(), BlockKind::Index);
+        assert_eq!(block.get_id(), 20);
+
+        block.set_free_space();
+        assert!(block.is_free_space());
+        assert!(!block.is_index());
+
         block.set_deleted();
-        assert_eq!(block.id, 0);
-        assert_eq!(block.get_id(), 0);
-        
+        assert!(block.is_deleted());
+
+        let mut block = Block::with_kind(BlockKind::Document, u64::MAX);
+        block.data[0] = 1;
+        let loaded = Block::from_bytes_vec(&block.to_bytes_vec()).unwrap();
+        assert_eq!(loaded.get_kind(), BlockKind::Document);
+        assert_eq!(loaded.get_id(), u64::MAX);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_unknown_kind() {
+        let mut bytes = Block::with_kind(BlockKind::Metadata, 1).to_bytes_vec();
+        assert_eq!(Block::from_bytes_vec(&bytes).unwrap().get_kind(), BlockKind::Metadata);
+
+        bytes[0] = 200;
+        assert!(matches!(Block::from_bytes_vec(&bytes), Err(FromBytesError::UnknownBlockKind(200))));
+
+    }
+
+    #[test]
+    fn test_deleted_block_checksum() {
+        let bytes_vec = Block::new().to_bytes_vec();
+        assert_eq!(bytes_vec.len(), DEFAULT_BLOCK_SIZE);
+        assert_eq!(bytes_vec[..1020], [0; 1020]);
+
+        let (stored, actual) = Block::checksums(&bytes_vec);
+        assert_eq!(stored, actual);
+        assert!(Block::from_bytes_vec(&bytes_vec).unwrap().is_deleted());
+    }
+
+    #[test]
+    fn test_block_sizes() {
+        for block_size in [MIN_BLOCK_SIZE, 256, 16 * 1024] {
+            let mut block = Block::with_size(block_size);
+            assert_eq!(block.data.len(), block_size - BLOCK_OVERHEAD);
+            assert_eq!(block.to_bytes_vec().len(), block_size);
+
+            block.set_kind(BlockKind::Document);
+            block.set_id(4);
+            block.set_next_block(9);
+            let last = block.data.len() - 1;
+            block.set_data(&[1, 2], last - 1).unwrap();
+
+            let bytes = block.to_bytes_vec();
+            assert_eq!(bytes.len(), block_size);
+            let (stored, actual) = Block::checksums(&bytes);
+            assert_eq!(stored, actual);
+
+            let loaded = Block::from_bytes_vec(&bytes).unwrap();
+            assert_eq!(loaded.get_size(), block_size);
+            assert_eq!(loaded.data, block.data);
+            assert_eq!(loaded.get_next_block(), Some(9));
+        }
+
+        assert!(!is_valid_block_size(MIN_BLOCK_SIZE - 1));
+        assert!(!is_valid_block_size(MAX_BLOCK_SIZE + 1));
+        assert!(matches!(Block::from_bytes_vec(&[1u8; 16]), Err(FromBytesError::ReadLenError)));
+    }
+}
@@ -1,42 +1,293 @@
-use std::{fs::File, io::{Read, Seek, SeekFrom, Write}, path::PathBuf, sync::{Arc, RwLock, RwLockWriteGuard}};
+use std::{collections::{BTreeMap, HashMap, HashSet}, fs::{File, OpenOptions, TryLockError}, io::{Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{atomic::AtomicU64, Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockWriteGuard}, thread::JoinHandle, time::Duration};
 
-use crate::storage::{block::{Block, BLOCK_DATA_SIZE, TOTAL_BLOCK_SIZE}, serialization::{FromBytes, FromBytesError, ToBytes}};
+use crate::storage::{block::{Block, BlockKind, BLOCK_OVERHEAD, DEFAULT_BLOCK_SIZE}, cache::{BlockCache, CacheStats, DEFAULT_CACHE_BLOCKS}, free_space::FreeSpace, header::{FileHeader, HeaderError, HEADER_SIZE}, item_reader::ItemReader, memory::MemoryBuffer, mmap::{MmapReader, MmapWriter}, scan::{BlockScan, DocumentScan}, serialization::{FromBytes, FromBytesError, ToBytes}, slotted::SlottedPage, wal::{Journal, Wal}};
+
+// Every item starts with its length, so readers can drop the padding of the last block
+pub const ITEM_LENGTH_SIZE: usize = 8;
+
+// Largest item a chain may hold by default, a longer chain is taken for a corrupt one
+pub const DEFAULT_MAX_ITEM_SIZE: usize = 1 << 30;
+
+// Documents up to a quarter of a block's data share slotted pages, larger ones get a chain of their own
+const SLOTTED_SHARE: usize = 4;
 
 #[derive(Clone, Debug)]
 pub enum StorageOption {
     File(PathBuf),
+    Memory(MemoryBuffer), // clones of the buffer share the data, so pass one to both the writer and reader
+    Mmap(PathBuf),
+}
+
+// Settings of a storage handle, the block size only matters when the file is created
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StorageConfig {
+    pub block_size: usize,
+    pub cache_blocks: usize, // 0 turns the block cache off
+    pub max_item_size: usize, // larger items are refused when written and reported as corrupt when read
+    pub sync_policy: SyncPolicy,
+    pub load_free_space: bool, // false opens with an empty pool and leaves the list on disk alone, for recovering a broken one
+    pub allow_pending_log: bool, // opens read only next to a log a crash left unapplied, for checking the blocks as they are
+}
+
+// When written data is forced to disk. A crash of the process alone never loses a commit, the OS
+// still has it, but a power loss can take whatever wasn't synced yet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    Never,              // left to the OS, a power loss can lose recent commits or leave one half written
+    OnCommit,           // a commit returns once it's on disk
+    Periodic(Duration), // a background thread syncs this often, a power loss can cost that much work
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self { block_size: DEFAULT_BLOCK_SIZE, cache_blocks: DEFAULT_CACHE_BLOCKS, max_item_size: DEFAULT_MAX_ITEM_SIZE, sync_policy: SyncPolicy::OnCommit, load_free_space: true, allow_pending_log: false }
+    }
+}
+
+impl StorageOption {
+    // An empty storage of the same kind, for building a copy that later replaces this one.
+    // Files get a sibling, anything a crash left there from an earlier attempt is removed
+    pub fn scratch(&self) -> std::io::Result<Self> {
+        let sibling = |path: &Path| -> std::io::Result<PathBuf> {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".compact");
+            let sibling = PathBuf::from(name);
+            for leftover in [sibling.clone(), Wal::path_for(&sibling), StorageLock::path_for(&sibling)] {
+                match std::fs::remove_file(leftover) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                    _ => {},
+                }
+            }
+            Ok(sibling)
+        };
+        Ok(match self {
+            Self::File(path) => Self::File(sibling(path)?),
+            Self::Mmap(path) => Self::Mmap(sibling(path)?),
+            Self::Memory(_) => Self::Memory(MemoryBuffer::new()),
+        })
+    }
+
+    // Moves a scratch copy into place, a file is swapped by one rename so a crash leaves either the old or the new one
+    pub fn replace_with(&self, scratch: &Self) -> std::io::Result<()> {
+        match (self, scratch) {
+            (Self::File(path) | Self::Mmap(path), Self::File(from) | Self::Mmap(from)) => {
+                // both logs must be empty by now, one left over would be replayed onto the new file
+                remove_applied_log(path)?;
+                remove_applied_log(from)?;
+                std::fs::remove_file(StorageLock::path_for(from))?; // the copy is closed, and the lock of `path` stays
+                std::fs::rename(from, path)?;
+                // the rename is an entry in the directory, it only survives a crash once that is synced
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+                File::open(dir)?.sync_all()
+            },
+            (Self::Memory(buffer), Self::Memory(from)) => buffer.set_contents(from.to_vec()?),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Can't replace a storage with one of another kind")),
+        }
+    }
+}
+
+// Removes the log of the file at `path`, refusing to when it still holds transactions
+fn remove_applied_log(path: &Path) -> std::io::Result<()> {
+    let log_path = Wal::path_for(path);
+    if !log_path.exists() {
+        return Ok(());
+    }
+    if !Wal::open(&log_path)?.committed()?.is_empty() {
+        return Err(std::io::Error::other(format!("{} still holds transactions", log_path.display())));
+    }
+    std::fs::remove_file(log_path)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpenMode {
+    CreateNew,    // fails if the file already exists
+    OpenExisting, // fails if the file is missing
+    OpenOrCreate,
+    ReadOnly,     // existing file, writes are refused
+}
+
+// Advisory lock on a block file, released when the handle is closed. Writers take it exclusive and
+// read only storages shared, so a second writer, or a writer next to readers, is refused instead of
+// corrupting the file. It's taken on "data.db.lock" rather than the block file itself, which a
+// compaction replaces, so a handle keeps its lock across the swap
+pub struct StorageLock(File);
+
+impl StorageLock {
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".lock");
+        PathBuf::from(name)
+    }
+
+    // None when another handle holds a lock this one conflicts with
+    fn acquire(path: &Path, exclusive: bool) -> std::io::Result<Option<Self>> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(Self::path_for(path))?;
+        Ok(lock_file(&file, exclusive)?.then_some(Self(file)))
+    }
 }
 
-pub trait WriteSeek: Write + Seek {}
-impl<T: Write + Seek> WriteSeek for T {}
+// False when another handle holds a lock this one conflicts with
+fn lock_file(file: &File, exclusive: bool) -> std::io::Result<bool> {
+    let result = match exclusive {
+        true => file.try_lock(),
+        false => file.try_lock_shared(),
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
+
+pub trait WriteSeek: Write + Seek + Send + Sync {
+    // Makes everything written so far durable, backends without a disk behind them only flush
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+impl WriteSeek for File {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
+
+// Positional reads leave no cursor behind, so any number of threads can read at once
+pub trait ReadAt: Send + Sync {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()>;
+    fn size(&self) -> std::io::Result<u64>;
+
+    // Hands `len` bytes at `offset` to `visit`, backends that already hold the bytes in memory skip the copy
+    fn visit_at(&self, offset: u64, len: usize, visit: &mut dyn FnMut(&[u8])) -> std::io::Result<()> {
+        let mut buf = vec![0u8; len];
+        self.read_exact_at(&mut buf, offset)?;
+        visit(&buf);
+        Ok(())
+    }
+}
+
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(self, buf, offset)? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                n => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
 
-pub trait ReadSeek: Read + Seek {}
-impl<T: Read + Seek> ReadSeek for T {}
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum BlockSeek{
     Start(u64),
     Current(i64),
 }
 
 pub struct Writer{
-    stored_in: StorageOption,
-    fd: Arc<RwLock<Box<dyn WriteSeek>>>,
-    header_size: u64
+    fd: Arc<RwLock<Journal>>,
+    free_space: Mutex<FreeSpace>,
+    free_space_at_begin: Mutex<Option<FreeSpace>>, // restored when the open transaction is rolled back
+    cache: Option<Arc<BlockCache>>,
+    header_size: u64,
+    block_size: usize, // taken from the file header
+    sync_policy: SyncPolicy,
+    group_sync: Mutex<GroupSync>,
+    synced: Condvar, // signalled whenever a sync finishes
+    periodic_sync: Option<PeriodicSync>,
+    lock: Option<StorageLock>, // None for a buffer, it only lives in this process
+    mapped_written: Option<Arc<AtomicU64>>, // bytes an mmap storage wrote, its file grows ahead of them
+}
+
+// Writers that sync at the same time share one sync, whoever finds none running does it for everyone waiting.
+// Commits share syncs of the log the same way
+#[derive(Debug, Default)]
+struct GroupSync {
+    synced_writes: u64, // every write up to this one is on disk
+    logged: u64, // every commit logged with a lower sequence number is on disk and applied
+    running: bool,
+}
+
+// Background thread behind SyncPolicy::Periodic, stopped when dropped
+struct PeriodicSync {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicSync {
+    // Only syncs the journal, blocks still waiting in the cache reach the file on flush
+    fn start(journal: Arc<RwLock<Journal>>, interval: Duration) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let (stopped, wake) = &*stop;
+                loop {
+                    let guard = stopped.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    let (guard, _) = wake.wait_timeout_while(guard, interval, |stopped| !*stopped)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    if *guard {
+                        return;
+                    }
+                    drop(guard);
+                    if let Ok(mut journal) = journal.write() {
+                        let _ = journal.sync(); // a failed sync is tried again on the next tick
+                    }
+                }
+            })
+        };
+        Self { stop, thread: Some(thread) }
+    }
+}
+
+impl Drop for PeriodicSync {
+    fn drop(&mut self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+        wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum WriterError {
     Io(std::io::Error),
     LockError(String), // משתמשים ב-String במקום PoisonError כדי להימנע מבעיות גנריות
+    HeaderError(HeaderError),
+    ReadOnly,
+    TransactionAlreadyOpen,
+    BlockSizeMismatch { found: usize, expected: usize }, // a block built for a file with another block size
+    ItemTooLarge { size: usize, limit: usize },
+    InUse, // another handle has the file open, for writing or reading
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ReaderError {
     Io(std::io::Error),
     LockError(String), // משתמשים ב-String במקום PoisonError כדי להימנע מבעיות גנריות
     FromBytesError(FromBytesError),
-    FromReaderError(String)
+    FromReaderError(String),
+    HeaderError(HeaderError),
+    Corrupted { position: u64, expected: u32, actual: u32 }, // the block's checksum doesn't match its contents
+    ChainCycle { head: u64, position: u64 }, // the chain starting at `head` links back to `position`
+    ChainTooLong { head: u64, limit: u64 },  // more blocks than the file has, or than the largest item needs
+    InUse, // another handle has the file open for writing
 }
 
 impl From<std::io::Error> for WriterError{
@@ -45,6 +296,12 @@ impl From<std::io::Error> for WriterError{
     }
 }
 
+impl From<HeaderError> for WriterError {
+    fn from(err: HeaderError) -> Self {
+        Self::HeaderError(err)
+    }
+}
+
 impl From<std::io::Error> for ReaderError{
     fn from(err: std::io::Error) -> Self {
         Self::Io(err)
@@ -57,173 +314,1851 @@ impl From<FromBytesError> for ReaderError {
     }
 }
 
-type WriterGuard<'a> = RwLockWriteGuard<'a, Box<dyn WriteSeek>>;
-type ReaderGuard<'a> = RwLockWriteGuard<'a, Box<(dyn ReadSeek + 'static)>>;
+impl From<HeaderError> for ReaderError {
+    fn from(err: HeaderError) -> Self {
+        Self::HeaderError(err)
+    }
+}
+
+// Errors of operations that both read and write, like opening a storage
+#[derive(Debug)]
+pub enum StorageError {
+    WriterError(WriterError),
+    ReaderError(ReaderError),
+    RollbackFailed { cause: Box<StorageError>, rollback: WriterError }, // the file may still hold part of the transaction
+}
+
+impl From<WriterError> for StorageError {
+    fn from(err: WriterError) -> Self {
+        Self::WriterError(err)
+    }
+}
+
+impl From<ReaderError> for StorageError {
+    fn from(err: ReaderError) -> Self {
+        Self::ReaderError(err)
+    }
+}
+
+type WriterGuard<'a> = RwLockWriteGuard<'a, Journal>;
 
 impl Writer{
     pub fn new(stored_in: StorageOption) -> Result<Self, WriterError> {
-        Ok(Self{
-            header_size: 0,
-            stored_in: stored_in.clone(),
-            fd : match stored_in {
-                StorageOption::File(path) => {
-                    let file = File::create(path)?;
-                    Arc::new(RwLock::new(Box::new(file) as Box<dyn WriteSeek>))
+        Self::open(stored_in, OpenMode::OpenOrCreate)
+    }
+
+    pub fn open(stored_in: StorageOption, mode: OpenMode) -> Result<Self, WriterError> {
+        Self::open_with_block_size(stored_in, mode, DEFAULT_BLOCK_SIZE)
+    }
+
+    // `block_size` is used when the file is created, an existing file keeps its own
+    pub fn open_with_block_size(stored_in: StorageOption, mode: OpenMode, block_size: usize) -> Result<Self, WriterError> {
+        Self::open_with_lock(stored_in, mode, block_size, None)
+    }
+
+    // Like open_with_block_size, with the lock already held, by a handle that had this file open before
+    fn open_with_lock(stored_in: StorageOption, mode: OpenMode, block_size: usize, lock: Option<StorageLock>) -> Result<Self, WriterError> {
+        if mode == OpenMode::ReadOnly {
+            return Err(WriterError::ReadOnly);
+        }
+
+        let mut mapped_written = None;
+        let (journal, header, lock) = match stored_in {
+            StorageOption::File(path) => {
+                let (mut file, lock) = Self::open_locked(&path, mode, lock)?;
+                let wal = Self::open_wal(&path, mode, &mut file)?;
+                let header = FileHeader::init_or_validate(&mut file, block_size)?;
+                (Journal::new(Box::new(file), Some(wal)), header, Some(lock))
+            },
+            StorageOption::Mmap(path) => {
+                let (file, lock) = Self::open_locked(&path, mode, lock)?;
+                let mut mmap = MmapWriter::new(file)?;
+                mapped_written = Some(mmap.written());
+                let wal = Self::open_wal(&path, mode, &mut mmap)?;
+                let header = FileHeader::init_or_validate(&mut mmap, block_size)?;
+                (Journal::new(Box::new(mmap), Some(wal)), header, Some(lock))
+            },
+            StorageOption::Memory(buffer) => {
+                // an empty buffer plays the part of a missing file
+                match (mode, buffer.is_empty()?) {
+                    (OpenMode::CreateNew, false) => return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into()),
+                    (OpenMode::OpenExisting, true) => return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+                    _ => {},
                 }
-            }
+                let mut cursor = buffer.cursor();
+                let header = FileHeader::init_or_validate(&mut cursor, block_size)?;
+                (Journal::new(Box::new(cursor), None), header, None) // nothing in memory survives a crash, so there's nothing to log
+            },
+        };
+
+        Ok(Self{
+            header_size: HEADER_SIZE as u64,
+            block_size: header.block_size as usize,
+            free_space: Mutex::new(FreeSpace::new()),
+            free_space_at_begin: Mutex::new(None),
+            cache: None,
+            fd: Arc::new(RwLock::new(journal)),
+            sync_policy: SyncPolicy::OnCommit,
+            group_sync: Mutex::new(GroupSync::default()),
+            synced: Condvar::new(),
+            periodic_sync: None,
+            lock,
+            mapped_written,
         })
     }
 
+    // Opens the log next to the block file, replaying a transaction a crash left half applied
+    fn open_wal(path: &Path, mode: OpenMode, file: &mut dyn WriteSeek) -> Result<Wal, WriterError> {
+        let mut wal = Wal::open(&Wal::path_for(path))?;
+        match mode {
+            OpenMode::CreateNew => wal.clear()?, // a leftover log belongs to some older file
+            _ => { wal.recover(file)?; },
+        }
+        Ok(wal)
+    }
+
+    // The lock comes before the log is replayed or the header touched, and stays with the handle.
+    // It's taken once the file opened, so a missing file doesn't leave a lock file behind
+    fn open_locked(path: &Path, mode: OpenMode, lock: Option<StorageLock>) -> Result<(File, StorageLock), WriterError> {
+        let file = Self::file_options(mode).open(path)?;
+        let lock = match lock {
+            Some(lock) => lock,
+            None => StorageLock::acquire(path, true)?.ok_or(WriterError::InUse)?,
+        };
+        Ok((file, lock))
+    }
+
+    fn file_options(mode: OpenMode) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        // File::create would truncate an existing database, so truncate is never set
+        options.read(true).write(true);
+        match mode {
+            OpenMode::CreateNew => options.create_new(true),
+            OpenMode::OpenOrCreate => options.create(true),
+            _ => &mut options,
+        };
+        options
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn data_size(&self) -> usize {
+        self.block_size - BLOCK_OVERHEAD
+    }
+
     fn get_seek(&self, seek: BlockSeek) -> Option<SeekFrom>{
         match seek {
-            BlockSeek::Current(pos) => Some(SeekFrom::Current((pos - 1) * TOTAL_BLOCK_SIZE as i64)),
-            BlockSeek::Start(pos) => Some(SeekFrom::Start(self.header_size + (pos * TOTAL_BLOCK_SIZE as u64))),
+            BlockSeek::Current(pos) => Some(SeekFrom::Current((pos - 1) * self.block_size as i64)),
+            BlockSeek::Start(pos) => Some(SeekFrom::Start(self.header_size + (pos * self.block_size as u64))),
         }
     }
 
-    fn get_writer(&self) -> Result<WriterGuard, WriterError> {
+    fn get_writer(&self) -> Result<WriterGuard<'_>, WriterError> {
         self.fd.write().map_err(|e| {
             WriterError::LockError(format!("Failed to acquire write lock {:?}", e))
         })
     }
 
-    pub fn write(&self, block: Block, seek: BlockSeek) -> Result<usize, WriterError>{
-        let buf = block.to_bytes_vec();
-        let mut writer = self.get_writer()?;
+    // Shares a cache with the reader, from then on written blocks stay in it until flush
+    pub fn set_cache(&mut self, cache: Arc<BlockCache>) {
+        self.cache = Some(cache);
+    }
 
-        writer.seek(self.get_seek(seek).unwrap())?;
-        let written_len = writer.write(&buf)?;
-        Ok(written_len)
+    pub fn mapped_written(&self) -> Option<Arc<AtomicU64>> {
+        self.mapped_written.clone()
     }
 
-    pub fn flush(&self) -> Result<(), WriterError> {
-        let mut writer = self.fd.write().map_err(|e| {
-            WriterError::LockError(format!("Failed to acquire write lock for flush: {:?}", e))
-        })?;
-        writer.flush().map_err(WriterError::Io)
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) -> Result<(), WriterError> {
+        self.get_writer()?.set_sync_policy(sync_policy);
+        self.sync_policy = sync_policy;
+        self.periodic_sync = match sync_policy {
+            SyncPolicy::Periodic(interval) => Some(PeriodicSync::start(self.fd.clone(), interval)),
+            _ => None,
+        };
+        Ok(())
     }
 
-}
+    // How many times the file was synced, a batch of writers synced together counts once
+    pub fn sync_count(&self) -> Result<u64, WriterError> {
+        Ok(self.get_writer()?.sync_count())
+    }
 
-pub struct Reader{
-    stored_in: StorageOption,
-    fd: Arc<RwLock<Box<dyn ReadSeek>>>,
-    header_size: u64
-}
+    // How many times the log was synced, commits that waited for the same sync count once
+    pub fn log_sync_count(&self) -> Result<u64, WriterError> {
+        Ok(self.get_writer()?.log_sync_count())
+    }
 
-impl Reader {
-    pub fn new(stored_in: StorageOption) -> Result<Self, ReaderError> {
-        Ok(Self{
-            header_size: 0,
-            stored_in: stored_in.clone(),
-            fd : match stored_in {
-                StorageOption::File(path) => {
-                    let file = File::open(path)?;
-                    Arc::new(RwLock::new(Box::new(file) as Box<dyn ReadSeek>))
+    // The header as the newest commit left it, before that commit reaches the file
+    pub fn logged_header(&self) -> Result<Option<FileHeader>, ReaderError> {
+        let writer = self.fd.read().map_err(|e| {
+            ReaderError::LockError(format!("Failed to acquire read lock {:?}", e))
+        })?;
+        Ok(writer.unapplied_write(0).map(FileHeader::decode).transpose()?)
+    }
+
+    fn write_block_at(&self, writer: &mut WriterGuard, position: u64, block: &Block) -> Result<(), WriterError> {
+        writer.seek(self.get_seek(BlockSeek::Start(position)).unwrap())?;
+        writer.write_all(&block.to_bytes_vec())?;
+        Ok(())
+    }
+
+    // Transactions write through, since the log needs the bytes at commit, every other write waits in the cache
+    fn put_block(&self, writer: &mut WriterGuard, position: u64, block: Block) -> Result<(), WriterError> {
+        match &self.cache {
+            Some(cache) if !writer.in_transaction() => {
+                for (evicted_at, evicted) in cache.insert_dirty(position, block) {
+                    self.write_block_at(writer, evicted_at, &evicted)?;
                 }
+                Ok(())
+            },
+            _ => self.write_block_at(writer, position, &block),
+        }
+    }
+
+    fn write_back(&self, writer: &mut WriterGuard) -> Result<(), WriterError> {
+        if let Some(cache) = &self.cache {
+            for (position, block) in cache.take_dirty() {
+                self.write_block_at(writer, position, &block)?;
             }
-        })
+        }
+        Ok(())
     }
 
-    fn get_seek(&self, seek: BlockSeek) -> SeekFrom{
-        match seek {
-            BlockSeek::Current(pos) => SeekFrom::Current((pos - 1) * TOTAL_BLOCK_SIZE as i64),
-            BlockSeek::Start(pos) => SeekFrom::Start(self.header_size + (pos * TOTAL_BLOCK_SIZE as u64)),
+    pub fn write(&self, block: Block, seek: BlockSeek) -> Result<usize, WriterError>{
+        if block.get_size() != self.block_size {
+            return Err(WriterError::BlockSizeMismatch { found: block.get_size(), expected: self.block_size });
         }
+        let mut writer = self.get_writer()?;
+
+        let offset = writer.seek(self.get_seek(seek).unwrap())?;
+        let position = offset.saturating_sub(self.header_size) / self.block_size as u64;
+        self.put_block(&mut writer, position, block)?;
+        writer.seek(SeekFrom::Start(offset + self.block_size as u64))?; // BlockSeek::Current goes on from here
+        Ok(self.block_size)
     }
 
-    fn get_reader(&self) -> Result<ReaderGuard, ReaderError> {
-        self.fd.write().map_err(|e| {
-            ReaderError::LockError(format!("Failed to acquire write lock {:?}", e))
+    pub fn write_header(&self, header: &FileHeader) -> Result<(), WriterError> {
+        let mut writer = self.get_writer()?;
+        header.write_to(&mut *writer)?;
+        Ok(())
+    }
+
+    fn get_free_space(&self) -> Result<MutexGuard<'_, FreeSpace>, WriterError> {
+        self.free_space.lock().map_err(|e| {
+            WriterError::LockError(format!("Failed to acquire free space lock {:?}", e))
         })
     }
 
-    pub fn read_block(&self, position: BlockSeek) -> Result<Block, ReaderError>{
-        let mut reader = self.get_reader()?;
-        let mut buf = [0u8;TOTAL_BLOCK_SIZE];
+    // Replaces the free space pool, e.g. with the one found on disk when opening a database
+    pub fn load_free_space(&self, free_space: FreeSpace) -> Result<(), WriterError> {
+        *self.get_free_space()? = free_space;
+        Ok(())
+    }
 
-        reader.seek(self.get_seek(position))?;
-        
-        reader.read_exact(&mut buf)?;
-        Ok(Block::from_bytes_vec(&buf)?)
+    pub fn free_block_count(&self) -> Result<usize, WriterError> {
+        Ok(self.get_free_space()?.len())
     }
 
-    pub fn read_full_item(&self, position: BlockSeek) -> Result<Vec<u8>, ReaderError>{
-        let mut out: Vec<u8> = vec![];
-        let mut search_pos = Some(position.clone());
-        while let Some(new_pos) = search_pos {
-            let block = self.read_block(new_pos.clone())?;
-            out.append(&mut block.get_data(BLOCK_DATA_SIZE, 0).map_err(|e| {
-                ReaderError::FromReaderError(e)
-            } )?);
-            search_pos = block.get_next_offset();
+    fn end_position(&self, writer: &mut WriterGuard) -> Result<u64, WriterError> {
+        let file_size = writer.seek(SeekFrom::End(0))?;
+        let file_end = file_size.saturating_sub(self.header_size).div_ceil(self.block_size as u64);
+        Ok(file_end.max(self.cache.as_ref().map_or(0, |cache| cache.dirty_end())))
+    }
+
+    // Writes a length prefixed item into the given blocks, linking each to the next.
+    // Only the first block carries `kind`, the rest are overflow blocks
+    fn write_chain(&self, writer: &mut WriterGuard, kind: BlockKind, id: u64, data: &[u8], positions: &[u64]) -> Result<(), WriterError> {
+        let mut item = (data.len() as u64).to_bytes_vec();
+        item.extend_from_slice(data);
+
+        for (i, position) in positions.iter().enumerate() {
+            let chunk = item.get(i * self.data_size()..).unwrap_or_default();
+            let chunk = &chunk[..chunk.len().min(self.data_size())];
+
+            let mut block = Block::with_size(self.block_size);
+            block.set_kind(if i == 0 { kind } else { BlockKind::Overflow });
+            block.set_id(id);
+            block.data[..chunk.len()].copy_from_slice(chunk);
+            if let Some(next_position) = positions.get(i + 1) {
+                block.set_next_block(*next_position);
+            }
+            self.put_block(writer, *position, block)?;
         }
-        Ok(out)
+        Ok(())
+    }
 
+    fn blocks_needed(&self, data_len: usize) -> usize {
+        (ITEM_LENGTH_SIZE + data_len).div_ceil(self.data_size())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::storage::block::Block;
-    use tempfile;
+    // Rewrites an item over the first `needed` blocks of its chain, appending blocks when the item
+    // outgrew it. The caller frees whatever is left of the old chain
+    fn rewrite_chain(&self, writer: &mut WriterGuard, kind: BlockKind, id: u64, data: &[u8], chain: &[u64], needed: usize) -> Result<Vec<u64>, WriterError> {
+        let mut chain = chain[..chain.len().min(needed)].to_vec();
+        if chain.len() < needed {
+            let end = self.end_position(writer)?;
+            chain.extend(end..end + (needed - chain.len()) as u64);
+        }
 
-    fn gen_block(first: bool) -> Block{
-        let mut block = Block{
-            id: 10,
-            data: [0u8;1008],
-            next_block_offset: {if first {1} else {0}}
-        };
-        block.data[10] = 10;
-        block.data[11] = 20;
-        block.data[12] = 30;
-        block
+        self.write_chain(writer, kind, id, data, &chain)?;
+        Ok(chain)
     }
 
-    fn get_file_expected_data() -> Vec<u8> {
-        let mut out_vec = vec![0u8;2016];
-        out_vec[10] = 10;
-        out_vec[11] = 20;
-        out_vec[12] = 30;
+    // Rewrites an item over its chain, blocks it no longer needs go back to the free space pool
+    pub fn rewrite_item(&self, kind: BlockKind, id: u64, data: &[u8], chain: &[u64]) -> Result<Vec<u64>, WriterError> {
+        let mut writer = self.get_writer()?;
+        let rewritten = self.rewrite_chain(&mut writer, kind, id, data, chain, self.blocks_needed(data.len()))?;
+        if let Some(surplus) = chain.get(rewritten.len()..) && !surplus.is_empty() {
+            let mut free_space = self.get_free_space()?;
+            self.zero_blocks(&mut writer, surplus)?;
+            free_space.release(surplus);
+            self.save_free_space(&mut writer, &mut free_space)?;
+        }
+        Ok(rewritten)
+    }
 
-        out_vec[1034] = 10;
-        out_vec[1035] = 20;
-        out_vec[1036] = 30;
+    // Spare blocks of the list's own chain go on the list, as many as it can take without needing them back
+    fn save_free_space(&self, writer: &mut WriterGuard, free_space: &mut FreeSpace) -> Result<(), WriterError> {
+        let mut chain = free_space.get_chain().to_vec();
+        let mut spare = 0;
+        while chain.len() - spare > self.blocks_needed(free_space.stored_len(spare + 1)) {
+            spare += 1;
+        }
+        let spare = chain.split_off(chain.len() - spare);
+        self.zero_blocks(writer, &spare)?;
+        free_space.release(&spare);
 
-        out_vec
+        let data = free_space.to_bytes_vec();
+        let chain = self.rewrite_chain(writer, BlockKind::FreeSpace, 0, &data, &chain, self.blocks_needed(data.len()))?;
+        free_space.set_chain(chain);
+        Ok(())
     }
- 
-    #[test]
-    fn test_writer_and_reader() {
-        let block = gen_block(true);
-        
-        let tmpfile = tempfile::NamedTempFile::new().unwrap();
-        let path = tmpfile.path().to_path_buf();
-        let writer_res = Writer::new(StorageOption::File(path.clone()));
-        assert!(writer_res.is_ok());
-        
-        let writer = writer_res.unwrap();
-        let result = writer.write(block, BlockSeek::Start(0)).unwrap();
-        assert_eq!(result, 1024);
-        
-        let block = gen_block(false);
-        let result = writer.write(block, BlockSeek::Current(1)).unwrap(); // right to the next block
-        assert_eq!(result, 1024);
 
-        let flush_res = writer.flush();
-        assert!(flush_res.is_ok());
+    // Stores the free space list, creating its chain if it has none yet, and returns where it starts
+    pub fn write_free_space(&self) -> Result<BlockSeek, WriterError> {
+        let mut writer = self.get_writer()?;
+        let mut free_space = self.get_free_space()?;
+        self.save_free_space(&mut writer, &mut free_space)?;
+        Ok(BlockSeek::Start(free_space.get_chain()[0]))
+    }
 
-        // Now test the reader
+    pub fn write_full_item(&self, id: u64, data: &[u8]) -> Result<BlockSeek, WriterError> {
+        self.write_item(BlockKind::Document, id, data)
+    }
 
-        let reader_res = Reader::new(StorageOption::File(path));
-        assert!(reader_res.is_ok());
-        let reader = reader_res.unwrap();
-        let data_res = reader.read_full_item(BlockSeek::Start(0));
-        assert!(data_res.is_ok());
-        let data = data_res.unwrap();
-        assert_eq!(data[0], get_file_expected_data()[0])
+    // Splits the item into as many blocks as needed, reusing free blocks before appending new ones
+    pub fn write_item(&self, kind: BlockKind, id: u64, data: &[u8]) -> Result<BlockSeek, WriterError> {
+        let mut writer = self.get_writer()?;
+        let mut free_space = self.get_free_space()?;
+
+        let needed = self.blocks_needed(data.len());
+        let (positions, reused) = self.allocate(&mut writer, &mut free_space, needed)?;
+
+        self.write_chain(&mut writer, kind, id, data, &positions)?;
+        if reused {
+            self.save_free_space(&mut writer, &mut free_space)?;
+        }
+        Ok(BlockSeek::Start(positions[0]))
+    }
+
+    // Writes a single block wherever there's room for it, and returns its position
+    pub fn write_new_block(&self, block: Block) -> Result<u64, WriterError> {
+        if block.get_size() != self.block_size {
+            return Err(WriterError::BlockSizeMismatch { found: block.get_size(), expected: self.block_size });
+        }
+        let mut writer = self.get_writer()?;
+        let mut free_space = self.get_free_space()?;
+
+        let (positions, reused) = self.allocate(&mut writer, &mut free_space, 1)?;
+        self.put_block(&mut writer, positions[0], block)?;
+        if reused {
+            self.save_free_space(&mut writer, &mut free_space)?;
+        }
+        Ok(positions[0])
+    }
+
+    // Picks `needed` positions, free blocks first, and tells whether the free space pool changed
+    fn allocate(&self, writer: &mut WriterGuard, free_space: &mut FreeSpace, needed: usize) -> Result<(Vec<u64>, bool), WriterError> {
+        let mut positions = free_space.take(needed);
+        let reused = !positions.is_empty();
+        if positions.len() < needed {
+            let end = self.end_position(writer)?;
+            positions.extend(end..end + (needed - positions.len()) as u64);
+        }
+        Ok((positions, reused))
+    }
+
+    // Zeroes the given blocks and returns them to the free space pool
+    pub fn release_blocks(&self, positions: &[u64]) -> Result<(), WriterError> {
+        let mut writer = self.get_writer()?;
+        let mut free_space = self.get_free_space()?;
+
+        self.zero_blocks(&mut writer, positions)?;
+        free_space.release(positions);
+        self.save_free_space(&mut writer, &mut free_space)
+    }
+
+    fn zero_blocks(&self, writer: &mut WriterGuard, positions: &[u64]) -> Result<(), WriterError> {
+        for position in positions {
+            self.put_block(writer, *position, Block::with_size(self.block_size))?;
+        }
+        Ok(())
+    }
+
+    fn get_free_space_at_begin(&self) -> Result<MutexGuard<'_, Option<FreeSpace>>, WriterError> {
+        self.free_space_at_begin.lock().map_err(|e| {
+            WriterError::LockError(format!("Failed to acquire free space lock {:?}", e))
+        })
+    }
+
+    // Stages every following write until commit, so they reach the file all together or not at all
+    pub fn begin(&self) -> Result<(), WriterError> {
+        let mut writer = self.get_writer()?;
+        if writer.in_transaction() {
+            return Err(WriterError::TransactionAlreadyOpen);
+        }
+        self.write_back(&mut writer)?; // earlier writes reach the file first, so the transaction sees it whole
+        *self.get_free_space_at_begin()? = Some(self.get_free_space()?.clone());
+        writer.begin()?;
+        Ok(())
+    }
+
+    // The log is synced without the journal locked, so the next transaction can begin meanwhile and
+    // commits waiting at the same time share a sync. Until the blocks reach the file, readers find
+    // them in the cache
+    pub fn commit(&self) -> Result<(), WriterError> {
+        let mut writer = self.get_writer()?;
+        self.get_free_space_at_begin()?.take();
+        let Some((cache, blocks)) = self.cache.as_ref().zip(self.staged_blocks(&writer)) else {
+            let positions: Vec<u64> = writer.staged().iter()
+                .filter(|entry| entry.offset >= self.header_size)
+                .map(|entry| (entry.offset - self.header_size) / self.block_size as u64)
+                .collect();
+            writer.commit()?;
+            if let Some(cache) = &self.cache {
+                cache.invalidate(&positions); // only after the blocks changed, or a racing reader could cache the old ones
+            }
+            return Ok(());
+        };
+
+        match writer.commit_logged()? {
+            Some(sequence) => {
+                for (position, block) in blocks {
+                    cache.insert_logged(position, block, sequence);
+                }
+                drop(writer);
+                self.wait_logged(sequence)
+            },
+            None => {
+                cache.invalidate(&blocks.iter().map(|(position, _)| *position).collect::<Vec<_>>());
+                Ok(())
+            },
+        }
+    }
 
+    // The blocks the open transaction wrote, in order. None when it wrote anything but whole blocks
+    // and the header, readers couldn't be shown that before it's applied
+    fn staged_blocks(&self, writer: &WriterGuard) -> Option<Vec<(u64, Block)>> {
+        let mut blocks = vec![];
+        for entry in writer.staged() {
+            if entry.offset == 0 && entry.bytes.len() == HEADER_SIZE {
+                continue; // read back through logged_header
+            }
+            let offset = entry.offset.checked_sub(self.header_size)?;
+            if offset % self.block_size as u64 != 0 || entry.bytes.len() != self.block_size {
+                return None;
+            }
+            blocks.push((offset / self.block_size as u64, Block::from_bytes_vec(&entry.bytes).ok()?));
+        }
+        Some(blocks)
+    }
+
+    // Waits until the commit logged as `sequence` is on disk and in the file. Whoever finds no sync
+    // running syncs the log for every commit logged so far
+    fn wait_logged(&self, sequence: u64) -> Result<(), WriterError> {
+        let mut group = self.get_group_sync()?;
+        loop {
+            if sequence < group.logged {
+                return Ok(()); // someone else's sync covered it
+            }
+            if !group.running {
+                break;
+            }
+            group = self.synced.wait(group).map_err(|e| {
+                WriterError::LockError(format!("Failed to wait for group sync {:?}", e))
+            })?;
+        }
+        group.running = true;
+        drop(group);
+
+        let result = self.sync_log();
+        let mut group = self.get_group_sync()?;
+        group.running = false;
+        if let Ok(applied_before) = result {
+            group.logged = group.logged.max(applied_before);
+        }
+        self.synced.notify_all();
+        result.map(|_| ())
+    }
+
+    // Syncs the log, with the journal unlocked, then applies the commits it holds. Returns the sequence
+    // number every commit below is applied
+    fn sync_log(&self) -> Result<u64, WriterError> {
+        let (sequence, log_sync) = {
+            let writer = self.get_writer()?;
+            match writer.logged_sequence().zip(writer.log_sync()) {
+                Some(logged) => logged,
+                None => {
+                    self.release_logged(writer.applied_before()); // applied by a write that couldn't wait
+                    return Ok(writer.applied_before());
+                },
+            }
+        };
+        log_sync.sync()?;
+
+        let mut writer = self.get_writer()?;
+        writer.apply_logged(sequence)?;
+        self.release_logged(writer.applied_before());
+        Ok(writer.applied_before())
     }
 
-}
\ No newline at end of file
+    fn release_logged(&self, applied_before: u64) {
+        if let Some(cache) = &self.cache {
+            cache.release_logged(applied_before);
+        }
+    }
+
+    pub fn in_transaction(&self) -> Result<bool, WriterError> {
+        Ok(self.get_writer()?.in_transaction())
+    }
+
+    // Drops the staged writes, and puts back the blocks they took from or gave to the free space pool
+    pub fn rollback(&self) -> Result<(), WriterError> {
+        let mut writer = self.get_writer()?;
+        writer.rollback();
+        if let Some(free_space) = self.get_free_space_at_begin()?.take() {
+            *self.get_free_space()? = free_space;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), WriterError> {
+        let mut writer = self.fd.write().map_err(|e| {
+            WriterError::LockError(format!("Failed to acquire write lock for flush: {:?}", e))
+        })?;
+        self.write_back(&mut writer)?;
+        writer.flush().map_err(WriterError::Io)
+    }
+
+    fn get_group_sync(&self) -> Result<MutexGuard<'_, GroupSync>, WriterError> {
+        self.group_sync.lock().map_err(|e| {
+            WriterError::LockError(format!("Failed to acquire group sync lock {:?}", e))
+        })
+    }
+
+    // Like flush, but only returns once everything written is on disk. When other threads sync at
+    // the same time, one sync covers all of them
+    pub fn sync(&self) -> Result<(), WriterError> {
+        let written = {
+            let mut writer = self.get_writer()?;
+            self.write_back(&mut writer)?;
+            writer.write_count()
+        };
+
+        let mut group = self.get_group_sync()?;
+        loop {
+            if group.synced_writes >= written {
+                return Ok(()); // someone else's sync started after our writes
+            }
+            if !group.running {
+                break;
+            }
+            group = self.synced.wait(group).map_err(|e| {
+                WriterError::LockError(format!("Failed to wait for group sync {:?}", e))
+            })?;
+        }
+        group.running = true;
+        drop(group);
+
+        let result = self.get_writer().and_then(|mut writer| {
+            let covered = writer.write_count();
+            writer.sync()?;
+            Ok(covered)
+        });
+
+        let mut group = self.get_group_sync()?;
+        group.running = false;
+        if let Ok(covered) = result {
+            group.synced_writes = group.synced_writes.max(covered);
+        }
+        self.synced.notify_all();
+        result.map(|_| ())
+    }
+
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.periodic_sync = None;
+        // blocks still waiting in the cache, and commits the log holds until the file is synced.
+        // There's no one left to report a failure to
+        let _ = match self.sync_policy {
+            SyncPolicy::Never => self.flush(),
+            _ => self.sync(),
+        };
+    }
+}
+
+pub struct Reader{
+    stored_in: StorageOption,
+    fd: Arc<dyn ReadAt>,
+    header_size: u64,
+    block_size: usize, // taken from the file header
+    cache: Option<Arc<BlockCache>>,
+    max_item_size: usize,
+}
+
+impl Reader {
+    pub fn new(stored_in: StorageOption) -> Result<Self, ReaderError> {
+        let (fd, header): (Arc<dyn ReadAt>, FileHeader) = match stored_in.clone() {
+            StorageOption::File(path) => {
+                let mut file = File::open(path)?;
+                let header = FileHeader::read_from(&mut file)?;
+                (Arc::new(file), header)
+            },
+            StorageOption::Mmap(path) => {
+                let mut file = File::open(path)?;
+                let header = FileHeader::read_from(&mut file)?;
+                (Arc::new(MmapReader::new(file)?), header)
+            },
+            StorageOption::Memory(buffer) => {
+                if buffer.is_empty()? {
+                    return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+                }
+                let header = FileHeader::read_from(&mut buffer.cursor())?;
+                (Arc::new(buffer), header)
+            },
+        };
+
+        Ok(Self{
+            header_size: HEADER_SIZE as u64,
+            block_size: header.block_size as usize,
+            stored_in,
+            cache: None,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            fd,
+        })
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.block_size
+    }
+
+    // A reader is shared between threads, so there's no cursor for BlockSeek::Current to move from
+    fn get_position(&self, seek: BlockSeek) -> Result<u64, ReaderError> {
+        match seek {
+            BlockSeek::Current(_) => Err(ReaderError::FromReaderError(String::from("Readers only seek from the start"))),
+            BlockSeek::Start(pos) => Ok(pos),
+        }
+    }
+
+    pub fn read_header(&self) -> Result<FileHeader, ReaderError> {
+        let mut buf = [0u8; HEADER_SIZE];
+        self.fd.read_exact_at(&mut buf, 0)?;
+        Ok(FileHeader::decode(&buf)?)
+    }
+
+    pub fn set_cache(&mut self, cache: Arc<BlockCache>) {
+        self.cache = Some(cache);
+    }
+
+    // Reads an mmap file only as far as the writer next to this reader wrote, see MmapWriter
+    pub fn follow_writer(&mut self, written: Arc<AtomicU64>) -> Result<(), ReaderError> {
+        if let StorageOption::Mmap(path) = &self.stored_in {
+            self.fd = Arc::new(MmapReader::following(File::open(path)?, written)?);
+        }
+        Ok(())
+    }
+
+    pub fn set_max_item_size(&mut self, max_item_size: usize) {
+        self.max_item_size = max_item_size;
+    }
+
+    fn read_block_at(&self, position: u64) -> Result<Block, ReaderError> {
+        if let Some(block) = self.cache.as_ref().and_then(|cache| cache.get(position)) {
+            return Ok(block);
+        }
+        let generation = self.cache.as_ref().map_or(0, |cache| cache.generation());
+
+        let mut block = Err(FromBytesError::ReadLenError);
+        let mut checksums = (0, 0);
+        let offset = self.header_size + position * self.block_size as u64;
+        self.fd.visit_at(offset, self.block_size, &mut |bytes| {
+            checksums = Block::checksums(bytes);
+            block = Block::from_bytes_vec(bytes);
+        })?;
+
+        let (expected, actual) = checksums;
+        if expected != actual {
+            return Err(ReaderError::Corrupted { position, expected, actual });
+        }
+        let block = block?;
+        if let Some(cache) = &self.cache {
+            cache.insert_clean(position, block.clone(), generation);
+        }
+        Ok(block)
+    }
+
+    pub fn read_block(&self, position: BlockSeek) -> Result<Block, ReaderError>{
+        self.read_block_at(self.get_position(position)?)
+    }
+
+    // Most blocks a valid chain can have
+    pub fn chain_limit(&self) -> Result<u64, ReaderError> {
+        Ok(self.block_count()?.min(self.max_item_size.div_ceil(self.block_size - BLOCK_OVERHEAD) as u64 + 1))
+    }
+
+    // Visits the blocks of the chain starting at `head` in order. A corrupt link could send it
+    // around in circles, so it stops at a block seen before, or once the chain is longer than any
+    // valid one, since no chain has more blocks than the file or than the largest item needs
+    fn walk_chain(&self, head: u64, mut visit: impl FnMut(u64, Block)) -> Result<(), ReaderError> {
+        let limit = self.chain_limit()?;
+        let mut seen = HashSet::new();
+        let mut next = Some(head);
+        while let Some(position) = next {
+            if !seen.insert(position) {
+                return Err(ReaderError::ChainCycle { head, position });
+            }
+            if seen.len() as u64 > limit {
+                return Err(ReaderError::ChainTooLong { head, limit });
+            }
+            let block = self.read_block_at(position)?;
+            next = block.get_next_block();
+            visit(position, block);
+        }
+        Ok(())
+    }
+
+    pub fn read_full_item(&self, position: BlockSeek) -> Result<Vec<u8>, ReaderError>{
+        let mut out: Vec<u8> = vec![];
+        self.walk_chain(self.get_position(position)?, |_, block| out.extend_from_slice(&block.data))?;
+
+        let item_len = u64::from_bytes_vec(&out[..ITEM_LENGTH_SIZE])? as usize;
+        if item_len > out.len() - ITEM_LENGTH_SIZE {
+            return Err(ReaderError::FromBytesError(FromBytesError::ReadLenError));
+        }
+        out.truncate(ITEM_LENGTH_SIZE + item_len);
+        out.drain(..ITEM_LENGTH_SIZE);
+        Ok(out)
+
+    }
+
+    // Reads the item at `position` a block at a time, for items too large to hold in memory
+    pub fn item_reader(&self, position: BlockSeek) -> Result<ItemReader<'_>, ReaderError> {
+        ItemReader::new(self, self.get_position(position)?)
+    }
+
+    // Positions of every block in the chain starting at `head`
+    pub fn chain_positions(&self, head: u64) -> Result<Vec<u64>, ReaderError> {
+        let mut positions = vec![];
+        self.walk_chain(head, |position, _| positions.push(position))?;
+        Ok(positions)
+    }
+
+    pub fn get_stored_in(&self) -> &StorageOption {
+        &self.stored_in
+    }
+
+    pub fn file_size(&self) -> Result<u64, ReaderError> {
+        Ok(self.fd.size()?)
+    }
+
+    // Every block with its position, for exports, rebuilds and debugging
+    pub fn scan(&self) -> Result<BlockScan<'_>, ReaderError> {
+        Ok(BlockScan::new(self, self.block_count()?))
+    }
+
+    // Every document with its id, found by scanning the blocks rather than through an index
+    pub fn documents(&self) -> Result<DocumentScan<'_>, ReaderError> {
+        Ok(DocumentScan::new(self.scan()?))
+    }
+
+    pub fn block_count(&self) -> Result<u64, ReaderError> {
+        let file_size = self.fd.size()?;
+        let file_blocks = file_size.saturating_sub(self.header_size) / self.block_size as u64;
+        Ok(file_blocks.max(self.cache.as_ref().map_or(0, |cache| cache.dirty_end())))
+    }
+}
+
+// Slotted pages the storage knows more about than the reader does
+#[derive(Debug, Default)]
+struct PageState {
+    room: BTreeMap<u64, usize>, // pages read or written since open and their free bytes, small documents try these first
+    staged: HashMap<u64, SlottedPage>, // pages changed by the open transaction, the reader only sees them after commit
+}
+
+// One handle for both reading and writing a block file
+pub struct BlockStorage {
+    reader: Reader,
+    writer: Option<Writer>, // None when opened read only
+    mode: OpenMode,
+    cache: Arc<BlockCache>,
+    pages: Mutex<PageState>,
+    config: StorageConfig,
+    shared_lock: Option<StorageLock>, // held while opened read only, a writer has its own lock
+}
+
+impl BlockStorage {
+    pub fn open(stored_in: StorageOption, mode: OpenMode) -> Result<Self, StorageError> {
+        Self::open_with_config(stored_in, mode, StorageConfig::default())
+    }
+
+    pub fn open_with_config(stored_in: StorageOption, mode: OpenMode, config: StorageConfig) -> Result<Self, StorageError> {
+        Self::open_with_lock(stored_in, mode, config, None)
+    }
+
+    // Opens the file again with the lock another handle on it held, see into_lock
+    pub fn open_with_lock(stored_in: StorageOption, mode: OpenMode, config: StorageConfig, lock: Option<StorageLock>) -> Result<Self, StorageError> {
+        let cache = Arc::new(BlockCache::new(config.cache_blocks));
+        let mut shared_lock = None;
+        let mut writer = match mode {
+            OpenMode::ReadOnly => {
+                shared_lock = match lock {
+                    Some(lock) => Some(lock),
+                    None => Self::lock_shared(&stored_in)?,
+                };
+                if !config.allow_pending_log {
+                    Self::check_wal_applied(&stored_in)?;
+                }
+                None
+            },
+            _ => Some(Writer::open_with_lock(stored_in.clone(), mode, config.block_size, lock)?),
+        };
+        if let Some(writer) = writer.as_mut() {
+            writer.set_cache(cache.clone());
+            writer.set_sync_policy(config.sync_policy)?;
+        }
+        let mut reader = Reader::new(stored_in)?;
+        if let Some(written) = writer.as_ref().and_then(Writer::mapped_written) {
+            reader.follow_writer(written)?;
+        }
+        reader.set_cache(cache.clone());
+        reader.set_max_item_size(config.max_item_size);
+        let config = StorageConfig { block_size: reader.get_block_size(), ..config };
+        let storage = Self { reader, writer, mode, cache, pages: Mutex::new(PageState::default()), config, shared_lock };
+
+        let mut header = storage.read_header()?;
+        match (header.free_space_root, &storage.writer) {
+            (_, Some(_)) if !storage.config.load_free_space => {},
+            (Some(root), Some(writer)) => {
+                if !storage.reader.read_block(BlockSeek::Start(root))?.is_free_space() {
+                    return Err(ReaderError::FromReaderError(format!("Block {root} is not a free space block")).into());
+                }
+                let mut free_space = FreeSpace::from_bytes_vec(&storage.reader.read_full_item(BlockSeek::Start(root))?)
+                    .map_err(ReaderError::from)?;
+                free_space.set_chain(storage.reader.chain_positions(root)?);
+                writer.load_free_space(free_space)?;
+            },
+            (None, Some(writer)) => {
+                writer.begin()?;
+                let BlockSeek::Start(root) = writer.write_free_space()? else {
+                    unreachable!("write_free_space always returns an absolute position");
+                };
+                header.free_space_root = Some(root);
+                writer.write_header(&header)?;
+                writer.commit()?;
+            },
+            (_, None) => {}, // nothing gets freed or allocated without a writer
+        }
+        Ok(storage)
+    }
+
+    // Readers share the lock, so a writer can't start changing the file under them
+    fn lock_shared(stored_in: &StorageOption) -> Result<Option<StorageLock>, ReaderError> {
+        let (StorageOption::File(path) | StorageOption::Mmap(path)) = stored_in else {
+            return Ok(None); // a buffer only lives in this process
+        };
+        File::open(path)?; // a missing file gets no lock file either
+        Ok(Some(StorageLock::acquire(path, false)?.ok_or(ReaderError::InUse)?))
+    }
+
+    // Closes the storage but keeps its lock, for opening the file again without anyone getting in between
+    pub fn into_lock(mut self) -> Option<StorageLock> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.lock.take(),
+            None => self.shared_lock.take(),
+        }
+    }
+
+    // Only a writer can replay the log, until one does the blocks may hold a half applied transaction
+    fn check_wal_applied(stored_in: &StorageOption) -> Result<(), ReaderError> {
+        if !Self::has_pending_log(stored_in)? {
+            return Ok(());
+        }
+        let (StorageOption::File(path) | StorageOption::Mmap(path)) = stored_in else {
+            unreachable!("only files have a log");
+        };
+        Err(ReaderError::FromReaderError(format!("{} holds an unapplied transaction, open the file for writing to recover it", Wal::path_for(path).display())))
+    }
+
+    // Whether the log holds commits that may not have reached the blocks
+    pub fn has_pending_log(stored_in: &StorageOption) -> Result<bool, ReaderError> {
+        let (StorageOption::File(path) | StorageOption::Mmap(path)) = stored_in else {
+            return Ok(false);
+        };
+        let wal_path = Wal::path_for(path);
+        Ok(wal_path.exists() && !Wal::new(File::open(&wal_path)?).committed()?.is_empty())
+    }
+
+    pub fn get_mode(&self) -> OpenMode {
+        self.mode
+    }
+
+    pub fn get_block_size(&self) -> usize {
+        self.reader.get_block_size()
+    }
+
+    // The settings this storage runs with, the block size being the one of the file
+    pub fn get_config(&self) -> StorageConfig {
+        self.config
+    }
+
+    pub fn get_stored_in(&self) -> &StorageOption {
+        self.reader.get_stored_in()
+    }
+
+    // Bytes the file takes, blocks still waiting in the cache not included
+    pub fn file_size(&self) -> Result<u64, ReaderError> {
+        self.reader.file_size()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    fn get_writer(&self) -> Result<&Writer, WriterError> {
+        self.writer.as_ref().ok_or(WriterError::ReadOnly)
+    }
+
+    pub fn read_header(&self) -> Result<FileHeader, ReaderError> {
+        if let Some(writer) = &self.writer && let Some(header) = writer.logged_header()? {
+            return Ok(header);
+        }
+        self.reader.read_header()
+    }
+
+    pub fn write_header(&self, header: &FileHeader) -> Result<(), WriterError> {
+        self.get_writer()?.write_header(header)
+    }
+
+    pub fn read_block(&self, position: BlockSeek) -> Result<Block, ReaderError> {
+        self.reader.read_block(position)
+    }
+
+    pub fn read_full_item(&self, position: BlockSeek) -> Result<Vec<u8>, ReaderError> {
+        self.reader.read_full_item(position)
+    }
+
+    pub fn item_reader(&self, position: BlockSeek) -> Result<ItemReader<'_>, ReaderError> {
+        self.reader.item_reader(position)
+    }
+
+    // Like the reader's scans, changes of an open transaction aren't seen until it commits
+    pub fn scan(&self) -> Result<BlockScan<'_>, ReaderError> {
+        self.reader.scan()
+    }
+
+    pub fn documents(&self) -> Result<DocumentScan<'_>, ReaderError> {
+        self.reader.documents()
+    }
+
+    pub fn chain_positions(&self, head: u64) -> Result<Vec<u64>, ReaderError> {
+        self.reader.chain_positions(head)
+    }
+
+    pub fn block_count(&self) -> Result<u64, ReaderError> {
+        self.reader.block_count()
+    }
+
+    // An item the reader would refuse as corrupt is better never written
+    fn check_item_size(&self, data: &[u8]) -> Result<(), WriterError> {
+        if data.len() > self.config.max_item_size {
+            return Err(WriterError::ItemTooLarge { size: data.len(), limit: self.config.max_item_size });
+        }
+        Ok(())
+    }
+
+    pub fn write_full_item(&self, id: u64, data: &[u8]) -> Result<BlockSeek, WriterError> {
+        self.check_item_size(data)?;
+        self.get_writer()?.write_full_item(id, data)
+    }
+
+    pub fn write_item(&self, kind: BlockKind, id: u64, data: &[u8]) -> Result<BlockSeek, WriterError> {
+        self.check_item_size(data)?;
+        self.get_writer()?.write_item(kind, id, data)
+    }
+
+    pub fn rewrite_item(&self, kind: BlockKind, id: u64, data: &[u8], chain: &[u64]) -> Result<Vec<u64>, WriterError> {
+        self.check_item_size(data)?;
+        self.get_writer()?.rewrite_item(kind, id, data, chain)
+    }
+
+    // Replaces the free space pool with `free_space`, zeroing and adding the `release` blocks on top,
+    // and returns where the stored list starts
+    pub fn rebuild_free_space(&self, free_space: FreeSpace, release: &[u64]) -> Result<BlockSeek, WriterError> {
+        let writer = self.get_writer()?;
+        writer.load_free_space(free_space)?;
+        writer.release_blocks(release)?;
+        writer.write_free_space()
+    }
+
+    // Returns every block of the chain starting at `head` to the free space pool
+    pub fn release_chain(&self, head: u64) -> Result<(), StorageError> {
+        let writer = self.get_writer()?;
+        writer.release_blocks(&self.reader.chain_positions(head)?)?;
+        Ok(())
+    }
+
+    pub fn free_block_count(&self) -> Result<usize, WriterError> {
+        self.get_writer()?.free_block_count()
+    }
+
+    pub fn begin(&self) -> Result<(), WriterError> {
+        self.get_writer()?.begin()
+    }
+
+    pub fn commit(&self) -> Result<(), WriterError> {
+        self.get_writer()?.commit()?;
+        self.get_pages().staged.clear(); // the reader sees the committed pages now
+        Ok(())
+    }
+
+    pub fn rollback(&self) -> Result<(), WriterError> {
+        self.get_writer()?.rollback()?;
+        // pages the transaction changed are back to what the reader sees, the ones it created are gone
+        let mut pages = self.get_pages();
+        let staged: Vec<u64> = pages.staged.drain().map(|(position, _)| position).collect();
+        for position in staged {
+            match self.load_page(&pages, position) {
+                Ok(Some(page)) => { pages.room.insert(position, page.free_space()); },
+                _ => { pages.room.remove(&position); },
+            }
+        }
+        Ok(())
+    }
+
+    fn get_pages(&self) -> MutexGuard<'_, PageState> {
+        // like the cache, the state only holds hints and copies of what is written, a panic can't corrupt the file through it
+        self.pages.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Largest document that goes into a slotted page
+    pub fn max_slotted_len(&self) -> usize {
+        (self.get_block_size() - BLOCK_OVERHEAD) / SLOTTED_SHARE
+    }
+
+    // The page at `position`, None when the block there isn't a slotted page
+    fn load_page(&self, pages: &PageState, position: u64) -> Result<Option<SlottedPage>, ReaderError> {
+        if let Some(page) = pages.staged.get(&position) {
+            return Ok(Some(page.clone()));
+        }
+        let block = self.reader.read_block(BlockSeek::Start(position))?;
+        if block.get_kind() != BlockKind::Slotted {
+            return Ok(None);
+        }
+        Ok(Some(SlottedPage::from_bytes_vec(&block.data)?))
+    }
+
+    fn page_block(&self, page: &SlottedPage) -> Block {
+        let mut block = Block::with_size(self.get_block_size());
+        block.set_kind(BlockKind::Slotted);
+        block.data = page.to_bytes_vec();
+        block
+    }
+
+    fn note_page(&self, pages: &mut PageState, position: u64, page: SlottedPage) -> Result<(), WriterError> {
+        if page.free_space() >= SlottedPage::entry_size(0) {
+            pages.room.insert(position, page.free_space());
+        } else {
+            pages.room.remove(&position);
+        }
+        if self.get_writer()?.in_transaction()? {
+            pages.staged.insert(position, page);
+        }
+        Ok(())
+    }
+
+    // Writes a changed page back, an emptied page goes back to the free space pool
+    fn store_page(&self, pages: &mut PageState, position: u64, page: SlottedPage) -> Result<(), WriterError> {
+        let writer = self.get_writer()?;
+        if page.is_empty() {
+            pages.room.remove(&position);
+            pages.staged.remove(&position);
+            return writer.release_blocks(&[position]);
+        }
+        writer.write(self.page_block(&page), BlockSeek::Start(position))?;
+        self.note_page(pages, position, page)
+    }
+
+    // Stores a document and returns the block the index should point at, either a slotted page
+    // shared with other small documents or the head of the document's own chain
+    pub fn write_document(&self, id: u64, data: &[u8]) -> Result<u64, StorageError> {
+        if data.len() > self.max_slotted_len() {
+            let BlockSeek::Start(head) = self.write_full_item(id, data)? else {
+                unreachable!("write_full_item always returns an absolute position");
+            };
+            return Ok(head);
+        }
+
+        let mut pages = self.get_pages();
+        let needed = SlottedPage::entry_size(data.len());
+        let candidates: Vec<u64> = pages.room.iter()
+            .filter(|(_, free)| **free >= needed)
+            .map(|(position, _)| *position)
+            .collect();
+        for position in candidates {
+            let Some(mut page) = self.load_page(&pages, position)? else {
+                pages.room.remove(&position); // freed and reused since, the note is stale
+                continue;
+            };
+            if page.insert(id, data) {
+                self.store_page(&mut pages, position, page)?;
+                return Ok(position);
+            }
+            pages.room.insert(position, page.free_space());
+        }
+
+        let mut page = SlottedPage::new(self.get_block_size() - BLOCK_OVERHEAD);
+        page.insert(id, data);
+        let position = self.get_writer()?.write_new_block(self.page_block(&page))?;
+        self.note_page(&mut pages, position, page)?;
+        Ok(position)
+    }
+
+    pub fn read_document(&self, id: u64, head: u64) -> Result<Vec<u8>, ReaderError> {
+        let mut pages = self.get_pages();
+        match self.load_page(&pages, head)? {
+            Some(page) => {
+                // pages written before open are only found this way, opening a collection reads them all
+                if page.free_space() >= SlottedPage::entry_size(0) {
+                    pages.room.insert(head, page.free_space());
+                }
+                page.get(id)
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| ReaderError::FromReaderError(format!("Document {id} is not in page {head}")))
+            },
+            None => self.reader.read_full_item(BlockSeek::Start(head)),
+        }
+    }
+
+    // Removes a document written by write_document, freeing its chain or its slot
+    pub fn release_document(&self, id: u64, head: u64) -> Result<(), StorageError> {
+        let mut pages = self.get_pages();
+        match self.load_page(&pages, head)? {
+            Some(mut page) => {
+                page.remove(id);
+                self.store_page(&mut pages, head, page)?;
+                Ok(())
+            },
+            None => {
+                drop(pages);
+                self.release_chain(head)
+            },
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), WriterError> {
+        self.get_writer()?.flush()
+    }
+
+    pub fn sync(&self) -> Result<(), WriterError> {
+        self.get_writer()?.sync()
+    }
+
+    pub fn sync_count(&self) -> Result<u64, WriterError> {
+        self.get_writer()?.sync_count()
+    }
+
+    pub fn log_sync_count(&self) -> Result<u64, WriterError> {
+        self.get_writer()?.log_sync_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{block::{Block, DEFAULT_DATA_SIZE}, wal::WalEntry};
+
+    fn gen_block(first: bool) -> Block{
+        let mut block = Block{
+            kind: if first {BlockKind::Document} else {BlockKind::Overflow},
+            flags: 0,
+            id: 10,
+            data: vec![0u8;DEFAULT_DATA_SIZE],
+            next_block: if first {Some(1)} else {None},
+        };
+        if first {
+            let item_len = (DEFAULT_DATA_SIZE * 2 - ITEM_LENGTH_SIZE) as u64;
+            block.data[..ITEM_LENGTH_SIZE].copy_from_slice(&item_len.to_bytes_vec());
+        }
+        block.data[10] = 10;
+        block.data[11] = 20;
+        block.data[12] = 30;
+        block
+    }
+
+    fn get_file_expected_data() -> Vec<u8> {
+        let mut out_vec = vec![0u8;DEFAULT_DATA_SIZE * 2 - ITEM_LENGTH_SIZE];
+        out_vec[2] = 10;
+        out_vec[3] = 20;
+        out_vec[4] = 30;
+
+        out_vec[1004] = 10;
+        out_vec[1005] = 20;
+        out_vec[1006] = 30;
+
+        out_vec
+    }
+ 
+    #[test]
+    fn test_writer_and_reader() {
+        let block = gen_block(true);
+        
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let writer_res = Writer::new(StorageOption::File(path.clone()));
+        assert!(writer_res.is_ok());
+        
+        let writer = writer_res.unwrap();
+        let result = writer.write(block, BlockSeek::Start(0)).unwrap();
+        assert_eq!(result, 1024);
+        
+        let block = gen_block(false);
+        let result = writer.write(block, BlockSeek::Current(1)).unwrap(); // right to the next block
+        assert_eq!(result, 1024);
+
+        let flush_res = writer.flush();
+        assert!(flush_res.is_ok());
+
+        // Now test the reader
+
+        let reader_res = Reader::new(StorageOption::File(path));
+        assert!(reader_res.is_ok());
+        let reader = reader_res.unwrap();
+        let data_res = reader.read_full_item(BlockSeek::Start(0));
+        assert!(data_res.is_ok());
+        let data = data_res.unwrap();
+        assert_eq!(data, get_file_expected_data())
+
+    }
+
+    #[test]
+    fn test_write_full_item() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let writer = Writer::new(StorageOption::File(path.clone())).unwrap();
+
+        let payload: Vec<u8> = (0..DEFAULT_DATA_SIZE * 2 + 5).map(|i| (i % 251) as u8).collect();
+        let head = writer.write_full_item(7, &payload).unwrap();
+        assert_eq!(head, BlockSeek::Start(0));
+
+        let second_head = writer.write_full_item(8, b"short").unwrap();
+        assert_eq!(second_head, BlockSeek::Start(3));
+        writer.flush().unwrap();
+
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+        assert_eq!(reader.block_count().unwrap(), 4);
+        assert_eq!(reader.chain_positions(0).unwrap(), vec![0, 1, 2]);
+        assert_eq!(reader.chain_positions(3).unwrap(), vec![3]);
+
+        let first_block = reader.read_block(BlockSeek::Start(0)).unwrap();
+        assert_eq!(first_block.get_id(), 7);
+        assert_eq!(first_block.get_next_block(), Some(1));
+        assert_eq!(first_block.get_kind(), BlockKind::Document);
+        assert_eq!(reader.read_block(BlockSeek::Start(2)).unwrap().get_kind(), BlockKind::Overflow);
+        assert_eq!(reader.read_block(BlockSeek::Start(2)).unwrap().get_id(), 7);
+
+        let data = reader.read_full_item(head).unwrap();
+        assert_eq!(data, payload);
+
+        let data = reader.read_full_item(second_head).unwrap();
+        assert_eq!(data, b"short");
+    }
+
+    #[test]
+    fn test_read_full_item_keeps_trailing_zeros() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let writer = Writer::new(StorageOption::File(path.clone())).unwrap();
+
+        let mut payload = vec![1u8; DEFAULT_DATA_SIZE - ITEM_LENGTH_SIZE];
+        payload.extend([0u8; 3]);
+        let head = writer.write_full_item(1, &payload).unwrap();
+
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+        assert_eq!(reader.chain_positions(0).unwrap(), vec![0, 1]);
+        assert_eq!(reader.read_full_item(head).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_read_full_item_bad_length() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let writer = Writer::new(StorageOption::File(path.clone())).unwrap();
+
+        let mut block = Block::with_kind(BlockKind::Document, 1);
+        block.data[..ITEM_LENGTH_SIZE].copy_from_slice(&(DEFAULT_DATA_SIZE as u64).to_bytes_vec());
+        writer.write(block, BlockSeek::Start(0)).unwrap();
+
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+        let result = reader.read_full_item(BlockSeek::Start(0));
+        assert!(matches!(result, Err(ReaderError::FromBytesError(FromBytesError::ReadLenError))));
+    }
+
+    #[test]
+    fn test_write_full_item_empty() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let writer = Writer::new(StorageOption::File(path.clone())).unwrap();
+
+        let head = writer.write_full_item(3, &[]).unwrap();
+        assert_eq!(head, BlockSeek::Start(0));
+
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+        let block = reader.read_block(head.clone()).unwrap();
+        assert_eq!(block.get_id(), 3);
+        assert_eq!(block.get_next_block(), None);
+        assert_eq!(reader.read_full_item(head).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_release_and_reuse_blocks() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let writer = Writer::new(StorageOption::File(path.clone())).unwrap();
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+
+        let first = writer.write_full_item(1, &[1u8; DEFAULT_DATA_SIZE * 2]).unwrap();
+        writer.write_full_item(2, b"second").unwrap();
+        assert_eq!(reader.block_count().unwrap(), 4);
+
+        writer.release_blocks(&reader.chain_positions(0).unwrap()).unwrap();
+        assert_eq!(writer.free_block_count().unwrap(), 3);
+        assert!(reader.read_block(first).unwrap().is_deleted());
+
+        // the free space list took a block of its own at the end
+        let free_space_block = reader.read_block(BlockSeek::Start(4)).unwrap();
+        assert!(free_space_block.is_free_space());
+        let stored = FreeSpace::from_bytes_vec(&reader.read_full_item(BlockSeek::Start(4)).unwrap()).unwrap();
+        assert_eq!(stored.len(), 3);
+
+        let head = writer.write_full_item(3, &[3u8; DEFAULT_DATA_SIZE]).unwrap();
+        assert_eq!(head, BlockSeek::Start(0));
+        assert_eq!(reader.chain_positions(0).unwrap(), vec![0, 1]);
+        assert_eq!(writer.free_block_count().unwrap(), 1);
+        assert_eq!(reader.block_count().unwrap(), 5);
+
+        // a chain can jump backwards over other blocks
+        writer.release_blocks(&[3]).unwrap();
+        let head = writer.write_full_item(4, &[4u8; DEFAULT_DATA_SIZE * 2]).unwrap();
+        assert_eq!(head, BlockSeek::Start(2));
+        assert_eq!(reader.chain_positions(2).unwrap(), vec![2, 3, 5]);
+        assert_eq!(reader.read_full_item(head).unwrap(), vec![4u8; DEFAULT_DATA_SIZE * 2]);
+    }
+
+    #[test]
+    fn test_header_written_on_create() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let writer = Writer::new(StorageOption::File(path.clone())).unwrap();
+        writer.write_full_item(1, b"data").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), (HEADER_SIZE + DEFAULT_BLOCK_SIZE) as u64);
+
+        let reader = Reader::new(StorageOption::File(path.clone())).unwrap();
+        let mut header = reader.read_header().unwrap();
+        assert_eq!(header.index_root, None);
+        assert_eq!(reader.block_count().unwrap(), 1);
+
+        header.index_root = Some(0);
+        writer.write_header(&header).unwrap();
+        assert_eq!(reader.read_header().unwrap().index_root, Some(0));
+        assert_eq!(reader.read_full_item(BlockSeek::Start(0)).unwrap(), b"data");
+
+        // reopening keeps both the header and the blocks
+        drop(writer);
+        let writer = Writer::new(StorageOption::File(path.clone())).unwrap();
+        writer.write_full_item(2, b"more").unwrap();
+        assert_eq!(reader.read_header().unwrap(), header);
+        assert_eq!(reader.block_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_open_foreign_file() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        std::fs::write(&path, vec![7u8; 4096]).unwrap();
+
+        let writer = Writer::new(StorageOption::File(path.clone()));
+        assert!(matches!(writer, Err(WriterError::HeaderError(HeaderError::ForeignFile))));
+        let reader = Reader::new(StorageOption::File(path));
+        assert!(matches!(reader, Err(ReaderError::HeaderError(HeaderError::ForeignFile))));
+    }
+
+    #[test]
+    fn test_open_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.db");
+
+        let result = Writer::open(StorageOption::File(path.clone()), OpenMode::OpenExisting);
+        assert!(matches!(result, Err(WriterError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound));
+        let result = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::ReadOnly);
+        assert!(matches!(result, Err(StorageError::ReaderError(ReaderError::Io(_)))));
+
+        let writer = Writer::open(StorageOption::File(path.clone()), OpenMode::CreateNew).unwrap();
+        writer.write_full_item(1, b"first").unwrap();
+
+        let result = Writer::open(StorageOption::File(path.clone()), OpenMode::CreateNew);
+        assert!(matches!(result, Err(WriterError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists));
+        let result = Writer::open(StorageOption::File(path.clone()), OpenMode::ReadOnly);
+        assert!(matches!(result, Err(WriterError::ReadOnly)));
+
+        // opening an existing file must not truncate it
+        drop(writer);
+        let writer = Writer::open(StorageOption::File(path.clone()), OpenMode::OpenExisting).unwrap();
+        writer.write_full_item(2, b"second").unwrap();
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+        assert_eq!(reader.read_full_item(BlockSeek::Start(0)).unwrap(), b"first");
+        assert_eq!(reader.read_full_item(BlockSeek::Start(1)).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_free_space_list_shrinks() {
+        let storage = BlockStorage::open(StorageOption::Memory(MemoryBuffer::new()), OpenMode::CreateNew).unwrap();
+        let data = vec![7u8; DEFAULT_DATA_SIZE * 300];
+        let BlockSeek::Start(head) = storage.write_full_item(1, &data).unwrap() else { unreachable!() };
+        storage.release_chain(head).unwrap();
+        let list_chain = |storage: &BlockStorage| storage.chain_positions(storage.read_header().unwrap().free_space_root.unwrap()).unwrap();
+        assert!(list_chain(&storage).len() > 1);
+
+        // taking back every free block leaves the list only the spare blocks of its own chain
+        storage.write_full_item(2, &data).unwrap();
+        assert_eq!(list_chain(&storage).len(), 1);
+        assert_eq!(storage.free_block_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_replace_refuses_a_pending_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.db");
+        let stored_in = StorageOption::File(path.clone());
+        drop(BlockStorage::open(stored_in.clone(), OpenMode::CreateNew).unwrap());
+        let scratch = stored_in.scratch().unwrap();
+        drop(BlockStorage::open(scratch.clone(), OpenMode::CreateNew).unwrap());
+
+        // a commit the old file never got, replayed onto the new one it would corrupt it
+        Wal::open(&Wal::path_for(&path)).unwrap().log(&[WalEntry { offset: 0, bytes: vec![1] }]).unwrap();
+        assert!(stored_in.replace_with(&scratch).is_err());
+        assert!(Wal::path_for(&path).exists());
+
+        Wal::open(&Wal::path_for(&path)).unwrap().clear().unwrap();
+        stored_in.replace_with(&scratch).unwrap();
+        assert!(!Wal::path_for(&path).exists());
+        assert!(!dir.path().join("blocks.db.compact").exists());
+    }
+
+    #[test]
+    fn test_file_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.db");
+
+        // one writer at a time, and no readers next to it
+        let storage = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::CreateNew).unwrap();
+        let result = Writer::open(StorageOption::File(path.clone()), OpenMode::OpenExisting);
+        assert!(matches!(result, Err(WriterError::InUse)));
+        let result = Writer::open(StorageOption::Mmap(path.clone()), OpenMode::OpenOrCreate);
+        assert!(matches!(result, Err(WriterError::InUse)));
+        let result = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::ReadOnly);
+        assert!(matches!(result, Err(StorageError::ReaderError(ReaderError::InUse))));
+
+        // readers share the file, but keep writers out until the last one is gone
+        drop(storage);
+        let first = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::ReadOnly).unwrap();
+        let second = BlockStorage::open(StorageOption::Mmap(path.clone()), OpenMode::ReadOnly).unwrap();
+        let result = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::OpenExisting);
+        assert!(matches!(result, Err(StorageError::WriterError(WriterError::InUse))));
+        drop(first);
+        assert!(matches!(Writer::open(StorageOption::File(path.clone()), OpenMode::OpenExisting), Err(WriterError::InUse)));
+        drop(second);
+        let writer = Writer::open(StorageOption::File(path.clone()), OpenMode::OpenExisting).unwrap();
+
+        // the lock sits next to the file, so it holds when another file is swapped in under the writer
+        let other = dir.path().join("other.db");
+        std::fs::copy(&path, &other).unwrap();
+        std::fs::rename(&other, &path).unwrap();
+        assert!(matches!(Writer::open(StorageOption::File(path.clone()), OpenMode::OpenExisting), Err(WriterError::InUse)));
+        drop(writer);
+        Writer::open(StorageOption::File(path), OpenMode::OpenExisting).unwrap();
+
+        // opening a file that isn't there leaves no lock file behind
+        let missing = dir.path().join("missing.db");
+        assert!(Writer::open(StorageOption::File(missing.clone()), OpenMode::OpenExisting).is_err());
+        assert!(BlockStorage::open(StorageOption::File(missing.clone()), OpenMode::ReadOnly).is_err());
+        assert!(!StorageLock::path_for(&missing).exists());
+    }
+
+    #[test]
+    fn test_block_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.db");
+
+        let storage = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::CreateNew).unwrap();
+        let free_space_root = storage.read_header().unwrap().free_space_root;
+        assert_eq!(free_space_root, Some(0));
+
+        let head = storage.write_full_item(1, &[9u8; DEFAULT_DATA_SIZE]).unwrap();
+        assert_eq!(storage.read_full_item(head.clone()).unwrap(), vec![9u8; DEFAULT_DATA_SIZE]);
+        storage.release_chain(1).unwrap();
+        assert_eq!(storage.free_block_count().unwrap(), 2);
+        drop(storage);
+
+        // the free space list is found again through the header
+        let storage = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::OpenExisting).unwrap();
+        assert_eq!(storage.free_block_count().unwrap(), 2);
+        assert_eq!(storage.write_full_item(2, b"reused").unwrap(), head);
+        drop(storage);
+
+        let storage = BlockStorage::open(StorageOption::File(path), OpenMode::ReadOnly).unwrap();
+        assert_eq!(storage.get_mode(), OpenMode::ReadOnly);
+        assert_eq!(storage.read_full_item(head).unwrap(), b"reused");
+        assert!(matches!(storage.write_full_item(3, b"nope"), Err(WriterError::ReadOnly)));
+        assert!(matches!(storage.release_chain(1), Err(StorageError::WriterError(WriterError::ReadOnly))));
+    }
+
+    #[test]
+    fn test_shared_reader_across_threads() {
+        const ITEMS: u64 = 64;
+        const THREADS: u64 = 8;
+        const ROUNDS: u64 = 50;
+
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let writer = Writer::new(StorageOption::File(path.clone())).unwrap();
+        let mut heads = vec![];
+        for id in 1..=ITEMS {
+            let payload = vec![id as u8; DEFAULT_DATA_SIZE * (id as usize % 3) + 1];
+            heads.push(writer.write_full_item(id, &payload).unwrap());
+        }
+        writer.flush().unwrap();
+
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let reader = &reader;
+                let heads = &heads;
+                scope.spawn(move || {
+                    for round in 0..ROUNDS {
+                        let id = (thread * ROUNDS + round) % ITEMS + 1;
+                        let head = heads[id as usize - 1].clone();
+                        let data = reader.read_full_item(head.clone()).unwrap();
+                        assert_eq!(data.len(), DEFAULT_DATA_SIZE * (id as usize % 3) + 1);
+                        assert!(data.iter().all(|b| *b == id as u8));
+                        assert_eq!(reader.read_block(head).unwrap().get_id(), id);
+                    }
+                });
+            }
+        });
+
+        // with no cursor to share, a relative seek has nothing to be relative to
+        assert!(matches!(reader.read_block(BlockSeek::Current(1)), Err(ReaderError::FromReaderError(_))));
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let memory = StorageOption::Memory(MemoryBuffer::new());
+
+        let result = Reader::new(memory.clone());
+        assert!(matches!(result, Err(ReaderError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound));
+        let result = Writer::open(memory.clone(), OpenMode::OpenExisting);
+        assert!(matches!(result, Err(WriterError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound));
+
+        let writer = Writer::open(memory.clone(), OpenMode::CreateNew).unwrap();
+        let head = writer.write_full_item(1, &[5u8; DEFAULT_DATA_SIZE + 10]).unwrap();
+        let result = Writer::open(memory.clone(), OpenMode::CreateNew);
+        assert!(matches!(result, Err(WriterError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists));
+
+        // the reader sees what the writer wrote, without any file involved
+        let reader = Reader::new(memory.clone()).unwrap();
+        assert_eq!(reader.block_count().unwrap(), 2);
+        assert_eq!(reader.read_full_item(head).unwrap(), vec![5u8; DEFAULT_DATA_SIZE + 10]);
+
+        let storage = BlockStorage::open(memory, OpenMode::OpenExisting).unwrap();
+        storage.release_chain(0).unwrap();
+        assert_eq!(storage.free_block_count().unwrap(), 2);
+        assert_eq!(storage.write_full_item(2, b"again").unwrap(), BlockSeek::Start(0));
+        assert_eq!(storage.read_full_item(BlockSeek::Start(0)).unwrap(), b"again");
+    }
+
+    #[test]
+    fn test_mmap_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let mmap = StorageOption::Mmap(dir.path().join("blocks.db"));
+
+        let writer = Writer::open(mmap.clone(), OpenMode::CreateNew).unwrap();
+        let first = writer.write_full_item(1, b"first").unwrap();
+        let mut reader = Reader::new(mmap.clone()).unwrap();
+        reader.follow_writer(writer.mapped_written().unwrap()).unwrap(); // the file is longer than what was written
+        assert_eq!(reader.read_full_item(first.clone()).unwrap(), b"first");
+
+        // blocks appended after the reader mapped the file are still found
+        let second = writer.write_full_item(2, &[8u8; DEFAULT_DATA_SIZE * 2]).unwrap();
+        assert_eq!(reader.block_count().unwrap(), 4);
+        assert_eq!(reader.read_full_item(second).unwrap(), vec![8u8; DEFAULT_DATA_SIZE * 2]);
+        writer.flush().unwrap();
+        drop(writer);
+
+        // and the file holds the same bytes any other backend would write
+        let reader = Reader::new(StorageOption::File(dir.path().join("blocks.db"))).unwrap();
+        assert_eq!(reader.read_full_item(first).unwrap(), b"first");
+
+        let storage = BlockStorage::open(mmap, OpenMode::OpenExisting).unwrap();
+        storage.release_chain(1).unwrap();
+        assert_eq!(storage.write_full_item(3, b"third").unwrap(), BlockSeek::Start(1));
+    }
+
+    #[test]
+    fn test_corrupted_block() {
+        let buffer = MemoryBuffer::new();
+        let writer = Writer::new(StorageOption::Memory(buffer.clone())).unwrap();
+        writer.write_full_item(1, &[1u8; DEFAULT_DATA_SIZE]).unwrap();
+        writer.write_full_item(2, b"untouched").unwrap();
+
+        // flip one bit in the data of the second block of the first chain
+        let mut cursor = buffer.cursor();
+        let flipped_at = HEADER_SIZE as u64 + DEFAULT_BLOCK_SIZE as u64 + 100;
+        cursor.seek(SeekFrom::Start(flipped_at)).unwrap();
+        let mut byte = [0u8];
+        std::io::Read::read_exact(&mut cursor, &mut byte).unwrap();
+        cursor.seek(SeekFrom::Start(flipped_at)).unwrap();
+        cursor.write_all(&[byte[0] ^ 1]).unwrap();
+
+        let reader = Reader::new(StorageOption::Memory(buffer)).unwrap();
+        assert!(reader.read_block(BlockSeek::Start(0)).is_ok());
+        let result = reader.read_block(BlockSeek::Start(1));
+        assert!(matches!(result, Err(ReaderError::Corrupted { position: 1, expected, actual }) if expected != actual));
+        let result = reader.read_full_item(BlockSeek::Start(0));
+        assert!(matches!(result, Err(ReaderError::Corrupted { position: 1, .. })));
+        assert_eq!(reader.read_full_item(BlockSeek::Start(2)).unwrap(), b"untouched");
+    }
+
+    #[test]
+    fn test_chain_cycle() {
+        let buffer = MemoryBuffer::new();
+        let writer = Writer::new(StorageOption::Memory(buffer.clone())).unwrap();
+        writer.write_full_item(1, &[1u8; DEFAULT_DATA_SIZE * 2]).unwrap();
+
+        // the last block of the chain links back to the one before it
+        let reader = Reader::new(StorageOption::Memory(buffer.clone())).unwrap();
+        let mut last = reader.read_block(BlockSeek::Start(2)).unwrap();
+        last.set_next_block(1);
+        writer.write(last, BlockSeek::Start(2)).unwrap();
+
+        let result = reader.read_full_item(BlockSeek::Start(0));
+        assert!(matches!(result, Err(ReaderError::ChainCycle { head: 0, position: 1 })));
+        let result = reader.chain_positions(0);
+        assert!(matches!(result, Err(ReaderError::ChainCycle { head: 0, position: 1 })));
+    }
+
+    #[test]
+    fn test_chain_too_long() {
+        let buffer = MemoryBuffer::new();
+        let storage = BlockStorage::open(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew).unwrap();
+        let BlockSeek::Start(head) = storage.write_full_item(1, &[1u8; DEFAULT_DATA_SIZE * 3]).unwrap() else { panic!() };
+        storage.flush().unwrap();
+        drop(storage);
+
+        // four blocks are more than an item of at most one block's data may take
+        let config = StorageConfig { max_item_size: DEFAULT_DATA_SIZE, ..StorageConfig::default() };
+        let storage = BlockStorage::open_with_config(StorageOption::Memory(buffer), OpenMode::OpenExisting, config).unwrap();
+        let result = storage.read_full_item(BlockSeek::Start(head));
+        assert!(matches!(result, Err(ReaderError::ChainTooLong { limit: 2, .. })));
+
+        let result = storage.write_full_item(2, &[2u8; DEFAULT_DATA_SIZE + 1]);
+        assert!(matches!(result, Err(WriterError::ItemTooLarge { size, limit: DEFAULT_DATA_SIZE }) if size == DEFAULT_DATA_SIZE + 1));
+    }
+
+    #[test]
+    fn test_transaction_commit_and_rollback() {
+        let buffer = MemoryBuffer::new();
+        let storage = BlockStorage::open(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew).unwrap();
+        let BlockSeek::Start(head) = storage.write_full_item(1, &[1u8; DEFAULT_DATA_SIZE]).unwrap() else { panic!() };
+        storage.release_chain(head).unwrap();
+        storage.flush().unwrap();
+        let bytes = buffer.to_vec().unwrap();
+
+        // nothing reaches the blocks before commit, and a rollback hands the taken blocks back
+        storage.begin().unwrap();
+        assert!(matches!(storage.begin(), Err(WriterError::TransactionAlreadyOpen)));
+        storage.write_full_item(2, &[2u8; DEFAULT_DATA_SIZE * 3]).unwrap();
+        assert_eq!(storage.free_block_count().unwrap(), 0);
+        assert_eq!(buffer.to_vec().unwrap(), bytes);
+        storage.rollback().unwrap();
+        assert_eq!(storage.free_block_count().unwrap(), 2);
+        assert_eq!(buffer.to_vec().unwrap(), bytes);
+
+        storage.begin().unwrap();
+        let BlockSeek::Start(head) = storage.write_full_item(3, b"kept").unwrap() else { panic!() };
+        storage.commit().unwrap();
+        assert_eq!(storage.read_full_item(BlockSeek::Start(head)).unwrap(), b"kept");
+        assert_eq!(storage.free_block_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_group_sync() {
+        const THREADS: u64 = 8;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("group.db");
+        let storage = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::CreateNew).unwrap();
+
+        let heads: Vec<BlockSeek> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (1..=THREADS).map(|id| {
+                let storage = &storage;
+                scope.spawn(move || {
+                    let head = storage.write_full_item(id, &[id as u8; 100]).unwrap();
+                    storage.sync().unwrap();
+                    head
+                })
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        let syncs = storage.sync_count().unwrap();
+        assert!((1..=THREADS).contains(&syncs));
+
+        // nothing new was written, so there's nothing to sync
+        storage.sync().unwrap();
+        assert_eq!(storage.sync_count().unwrap(), syncs);
+
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+        for (id, head) in (1..=THREADS).zip(heads) {
+            assert_eq!(reader.read_full_item(head).unwrap(), vec![id as u8; 100]);
+        }
+    }
+
+    #[test]
+    fn test_group_commit() {
+        const THREADS: u64 = 8;
+        const COMMITS: u64 = 10;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("group_commit.db");
+        let storage = BlockStorage::open(StorageOption::File(path.clone()), OpenMode::CreateNew).unwrap();
+        let syncs_before = storage.log_sync_count().unwrap();
+
+        // one transaction is open at a time, the next begins while the last one waits for the log
+        let heads: Vec<(u64, BlockSeek)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..THREADS).map(|thread| {
+                let storage = &storage;
+                scope.spawn(move || {
+                    let mut heads = vec![];
+                    for commit in 0..COMMITS {
+                        let id = thread * COMMITS + commit + 1;
+                        loop {
+                            match storage.begin() {
+                                Ok(()) => break,
+                                Err(WriterError::TransactionAlreadyOpen) => std::thread::yield_now(),
+                                Err(err) => panic!("{err:?}"),
+                            }
+                        }
+                        heads.push((id, storage.write_full_item(id, &[id as u8; 100]).unwrap()));
+                        storage.commit().unwrap();
+                    }
+                    heads
+                })
+            }).collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+        let syncs = storage.log_sync_count().unwrap() - syncs_before;
+        assert!((1..=THREADS * COMMITS).contains(&syncs), "{syncs} log syncs");
+        for (id, head) in &heads {
+            assert_eq!(storage.read_full_item(head.clone()).unwrap(), vec![*id as u8; 100]);
+        }
+        drop(storage);
+
+        let reader = Reader::new(StorageOption::File(path)).unwrap();
+        for (id, head) in heads {
+            assert_eq!(reader.read_full_item(head).unwrap(), vec![id as u8; 100]);
+        }
+    }
+
+    #[test]
+    fn test_periodic_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { sync_policy: SyncPolicy::Periodic(Duration::from_millis(5)), ..StorageConfig::default() };
+        let storage = BlockStorage::open_with_config(StorageOption::File(dir.path().join("periodic.db")), OpenMode::CreateNew, config).unwrap();
+        storage.begin().unwrap();
+        storage.write_full_item(1, b"periodic").unwrap();
+        storage.commit().unwrap();
+
+        // commits don't sync the file themselves, the background thread does
+        let started = std::time::Instant::now();
+        while storage.sync_count().unwrap() == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "the background sync never ran");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let buffer = MemoryBuffer::new();
+        let storage = BlockStorage::open_with_config(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew, StorageConfig { cache_blocks: 4, ..StorageConfig::default() }).unwrap();
+        let size = buffer.len().unwrap();
+
+        // written blocks wait in the cache, but reads already see them
+        let BlockSeek::Start(head) = storage.write_full_item(1, &[7u8; DEFAULT_DATA_SIZE * 2]).unwrap() else { panic!() };
+        assert_eq!(buffer.len().unwrap(), size);
+        assert_eq!(storage.cache_stats().dirty, 3);
+        assert_eq!(storage.block_count().unwrap(), head + 3);
+        assert_eq!(storage.read_full_item(BlockSeek::Start(head)).unwrap(), vec![7u8; DEFAULT_DATA_SIZE * 2]);
+        assert_eq!(storage.cache_stats().misses, 0);
+
+        storage.flush().unwrap();
+        assert_eq!(storage.cache_stats().dirty, 0);
+        let reader = Reader::new(StorageOption::Memory(buffer.clone())).unwrap();
+        assert_eq!(reader.read_full_item(BlockSeek::Start(head)).unwrap(), vec![7u8; DEFAULT_DATA_SIZE * 2]);
+
+        // more blocks than fit, the oldest dirty ones are written out to make room
+        let BlockSeek::Start(other) = storage.write_full_item(2, &[9u8; DEFAULT_DATA_SIZE * 4]).unwrap() else { panic!() };
+        assert!(storage.cache_stats().evictions > 0);
+        assert_eq!(storage.read_full_item(BlockSeek::Start(other)).unwrap(), vec![9u8; DEFAULT_DATA_SIZE * 4]);
+        let stats = storage.cache_stats();
+        assert!(stats.hits > 0 && stats.misses > 0);
+        assert!(stats.cached <= 4);
+
+        drop(storage); // dropping writes back whatever is left
+        let reader = Reader::new(StorageOption::Memory(buffer)).unwrap();
+        assert_eq!(reader.read_full_item(BlockSeek::Start(other)).unwrap(), vec![9u8; DEFAULT_DATA_SIZE * 4]);
+    }
+
+    #[test]
+    fn test_custom_block_size() {
+        let buffer = MemoryBuffer::new();
+        let writer = Writer::open_with_block_size(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew, 128).unwrap();
+        let payload: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let head = writer.write_full_item(1, &payload).unwrap();
+        writer.flush().unwrap();
+
+        let chunks = (ITEM_LENGTH_SIZE + payload.len()).div_ceil(128 - BLOCK_OVERHEAD) as u64;
+        assert_eq!(buffer.len().unwrap(), HEADER_SIZE as u64 + chunks * 128);
+
+        let result = writer.write(Block::with_kind(BlockKind::Document, 2), BlockSeek::Start(0));
+        assert!(matches!(result, Err(WriterError::BlockSizeMismatch { found: DEFAULT_BLOCK_SIZE, expected: 128 })));
+
+        let reader = Reader::new(StorageOption::Memory(buffer.clone())).unwrap();
+        assert_eq!(reader.block_count().unwrap(), chunks);
+        assert_eq!(reader.read_full_item(head).unwrap(), payload);
+
+        // reopening asks for the default size, but the file keeps its own
+        let writer = Writer::open(StorageOption::Memory(buffer), OpenMode::OpenExisting).unwrap();
+        assert_eq!(writer.get_block_size(), 128);
+    }
+}
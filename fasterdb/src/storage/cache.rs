@@ -0,0 +1,289 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{Mutex, MutexGuard}};
+
+use crate::storage::block::Block;
+
+pub const DEFAULT_CACHE_BLOCKS: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub cached: usize,
+    pub dirty: usize,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    block: Block,
+    dirty: bool, // changed since it was last written to the file
+    logged: Option<u64>, // committed, but the file only gets it once the log holding this sequence number is synced
+    used_at: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<u64, CacheEntry>,
+    recency: BTreeMap<u64, u64>, // used_at -> position, least recently used first
+    clock: u64,
+    generation: u64, // bumped by every write, so a reader can tell its disk read went stale
+    stats: CacheStats,
+}
+
+// Bounded LRU cache of blocks by position, shared by the reader and writer of one storage
+#[derive(Debug)]
+pub struct BlockCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl CacheState {
+    fn touch(&mut self, position: u64) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(&position) {
+            self.recency.remove(&entry.used_at);
+            entry.used_at = clock;
+            self.recency.insert(clock, position);
+        }
+    }
+
+    fn insert(&mut self, position: u64, block: Block, dirty: bool) {
+        self.clock += 1;
+        if let Some(old) = self.entries.insert(position, CacheEntry { block, dirty, logged: None, used_at: self.clock }) {
+            self.recency.remove(&old.used_at);
+        }
+        self.recency.insert(self.clock, position);
+    }
+
+    fn remove(&mut self, position: u64) -> Option<CacheEntry> {
+        let entry = self.entries.remove(&position)?;
+        self.recency.remove(&entry.used_at);
+        Some(entry)
+    }
+
+    // Blocks waiting for the log are the only copy readers can see, they're never evicted
+    fn least_recent(&self, clean_only: bool) -> Option<u64> {
+        self.recency.values().copied().find(|position| {
+            let entry = &self.entries[position];
+            entry.logged.is_none() && (!clean_only || !entry.dirty)
+        })
+    }
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, state: Mutex::new(CacheState::default()) }
+    }
+
+    fn get_state(&self) -> MutexGuard<'_, CacheState> {
+        // the state is only ever replaced whole, a panic can't leave it half updated
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self, position: u64) -> Option<Block> {
+        let mut state = self.get_state();
+        let block = state.entries.get(&position).map(|entry| entry.block.clone());
+        match block {
+            Some(_) => {
+                state.stats.hits += 1;
+                state.touch(position);
+            },
+            None => state.stats.misses += 1,
+        }
+        block
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.get_state().generation
+    }
+
+    // Caches a block read from the file, unless a write since `generation` may have made it stale.
+    // Readers never write, so when only dirty blocks are left to evict the block just isn't cached
+    pub fn insert_clean(&self, position: u64, block: Block, generation: u64) {
+        let mut state = self.get_state();
+        if self.capacity == 0 || state.generation != generation || state.entries.contains_key(&position) {
+            return;
+        }
+        if state.entries.len() >= self.capacity {
+            let Some(victim) = state.least_recent(true) else {
+                return;
+            };
+            state.remove(victim);
+            state.stats.evictions += 1;
+        }
+        state.insert(position, block, false);
+    }
+
+    // Keeps a written block until flush, returns the dirty blocks evicted to make room, which the caller must write
+    pub fn insert_dirty(&self, position: u64, block: Block) -> Vec<(u64, Block)> {
+        let mut state = self.get_state();
+        state.generation += 1;
+        if self.capacity == 0 {
+            state.remove(position); // a block still waiting for the log is older than this one
+            return vec![(position, block)];
+        }
+
+        let mut evicted = vec![];
+        while !state.entries.contains_key(&position) && state.entries.len() >= self.capacity {
+            let Some(victim) = state.least_recent(false) else {
+                break; // every block is waiting for the log
+            };
+            let entry = state.remove(victim).expect("the victim is cached");
+            state.stats.evictions += 1;
+            if entry.dirty {
+                evicted.push((victim, entry.block));
+            }
+        }
+        state.insert(position, block, true);
+        evicted
+    }
+
+    // Holds a committed block readers must see before it's applied to the file, whatever the capacity
+    pub fn insert_logged(&self, position: u64, block: Block, sequence: u64) {
+        let mut state = self.get_state();
+        state.generation += 1;
+        state.insert(position, block, false);
+        state.entries.get_mut(&position).expect("the block was just inserted").logged = Some(sequence);
+    }
+
+    // Blocks of commits below `applied_before` are in the file now, and can be evicted like any other
+    pub fn release_logged(&self, applied_before: u64) {
+        let mut state = self.get_state();
+        for entry in state.entries.values_mut() {
+            if entry.logged.is_some_and(|sequence| sequence < applied_before) {
+                entry.logged = None;
+            }
+        }
+    }
+
+    // Forgets blocks that were written around the cache
+    pub fn invalidate(&self, positions: &[u64]) {
+        let mut state = self.get_state();
+        state.generation += 1;
+        for position in positions {
+            state.remove(*position);
+        }
+    }
+
+    // Hands out every dirty block, lowest position first, and marks them clean
+    pub fn take_dirty(&self) -> Vec<(u64, Block)> {
+        let mut state = self.get_state();
+        let mut dirty: Vec<(u64, Block)> = state.entries.iter_mut()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(position, entry)| {
+                entry.dirty = false;
+                (*position, entry.block.clone())
+            })
+            .collect();
+        if !dirty.is_empty() {
+            state.generation += 1;
+        }
+        dirty.sort_by_key(|(position, _)| *position);
+        dirty
+    }
+
+    // Dirty blocks, and those waiting for the log, may lie past the end of the file, this is the first
+    // position after all of them
+    pub fn dirty_end(&self) -> u64 {
+        let state = self.get_state();
+        state.entries.iter()
+            .filter(|(_, entry)| entry.dirty || entry.logged.is_some())
+            .map(|(position, _)| position + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.get_state();
+        CacheStats {
+            cached: state.entries.len(),
+            dirty: state.entries.values().filter(|entry| entry.dirty).count(),
+            ..state.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(id: u64) -> Block {
+        let mut block = Block::new();
+        block.set_id(id);
+        block
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = BlockCache::new(2);
+        let generation = cache.generation();
+        cache.insert_clean(1, block(1), generation);
+        cache.insert_clean(2, block(2), generation);
+        assert_eq!(cache.get(1).unwrap().get_id(), 1); // 2 is now the least recently used
+
+        cache.insert_clean(3, block(3), generation);
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(1).unwrap().get_id(), 1);
+        assert_eq!(cache.get(3).unwrap().get_id(), 3);
+        assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 1, evictions: 1, cached: 2, dirty: 0 });
+    }
+
+    #[test]
+    fn test_dirty_blocks() {
+        let cache = BlockCache::new(2);
+        assert!(cache.insert_dirty(5, block(5)).is_empty());
+        assert!(cache.insert_dirty(1, block(1)).is_empty());
+        assert_eq!(cache.dirty_end(), 6);
+
+        // readers can't make room when every block is dirty
+        cache.insert_clean(7, block(7), cache.generation());
+        assert!(cache.get(7).is_none());
+
+        // a writer can, by taking the evicted block with it
+        let evicted = cache.insert_dirty(9, block(9));
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].0, 5);
+
+        let dirty = cache.take_dirty();
+        assert_eq!(dirty.iter().map(|(position, _)| *position).collect::<Vec<_>>(), vec![1, 9]);
+        assert!(cache.take_dirty().is_empty());
+        assert_eq!(cache.dirty_end(), 0);
+        assert_eq!(cache.stats().cached, 2);
+    }
+
+    #[test]
+    fn test_logged_blocks_stay_until_applied() {
+        let cache = BlockCache::new(1);
+        cache.insert_logged(4, block(4), 7);
+        cache.insert_logged(2, block(2), 8);
+        assert_eq!(cache.dirty_end(), 5);
+
+        // nothing can be evicted for a reader or a writer, the writer's block is written straight away
+        cache.insert_clean(3, block(3), cache.generation());
+        assert!(cache.get(3).is_none());
+        assert!(cache.insert_dirty(5, block(5)).is_empty());
+        assert_eq!(cache.stats().cached, 3);
+        assert_eq!(cache.take_dirty().len(), 1);
+
+        cache.release_logged(8);
+        assert_eq!(cache.dirty_end(), 3);
+        assert_eq!(cache.insert_dirty(6, block(6)).len(), 0);
+        assert!(cache.get(4).is_none());
+        assert_eq!(cache.get(2).unwrap().get_id(), 2);
+    }
+
+    #[test]
+    fn test_stale_read_is_not_cached() {
+        let cache = BlockCache::new(4);
+        let generation = cache.generation();
+        cache.invalidate(&[3]); // a write landed while the reader was at the disk
+        cache.insert_clean(3, block(3), generation);
+        assert!(cache.get(3).is_none());
+
+        let cache = BlockCache::new(0);
+        assert_eq!(cache.insert_dirty(1, block(1)).len(), 1);
+        cache.insert_clean(2, block(2), cache.generation());
+        assert_eq!(cache.stats().cached, 0);
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeSet;
+
+use crate::storage::serialization::{FromBytes, FromBytesError, SizeExtraction, ToBytes};
+
+// Pool of deleted block positions, stored in its own chain of free space blocks
+#[derive(Debug, Default, Clone)]
+pub struct FreeSpace {
+    positions: BTreeSet<u64>,
+    chain: Vec<u64>, // blocks holding the free space list itself
+}
+
+impl FreeSpace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    pub fn contains(&self, position: u64) -> bool {
+        self.positions.contains(&position)
+    }
+
+    // Hands out up to `count` free positions, lowest first
+    pub fn take(&mut self, count: usize) -> Vec<u64> {
+        let mut taken = vec![];
+        while taken.len() < count {
+            match self.positions.pop_first() {
+                Some(position) => taken.push(position),
+                None => break,
+            }
+        }
+        taken
+    }
+
+    pub fn release(&mut self, positions: &[u64]) {
+        self.positions.extend(positions);
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = u64> + '_ {
+        self.positions.iter().copied()
+    }
+
+    // Bytes the list takes stored, holding `extra` positions more than it does
+    pub fn stored_len(&self, extra: usize) -> usize {
+        std::mem::size_of::<usize>() + (self.positions.len() + extra) * 8
+    }
+
+    pub fn get_chain(&self) -> &[u64] {
+        &self.chain
+    }
+
+    pub fn set_chain(&mut self, chain: Vec<u64>) {
+        self.chain = chain;
+    }
+}
+
+impl ToBytes for FreeSpace {
+    fn to_bytes_vec(&self) -> Vec<u8> {
+        self.positions.iter().copied().collect::<Vec<u64>>().to_bytes_vec()
+    }
+}
+
+impl FromBytes for FreeSpace {
+    fn from_bytes_vec(bytes: &[u8]) -> Result<Self, FromBytesError> where Self:Sized {
+        let len_size = std::mem::size_of::<usize>();
+        if bytes.len() < len_size {
+            return Err(FromBytesError::ReadLenError);
+        }
+        let count = usize::from_bytes_vec(&bytes[..len_size])?;
+        let positions = &bytes[len_size..];
+        if count.checked_mul(8) != Some(positions.len()) { // the count comes from the file, it may be anything
+            return Err(FromBytesError::ReadLenError);
+        }
+
+        Ok(Self {
+            positions: positions.chunks(8).map(u64::from_bytes_vec).collect::<Result<_, _>>()?,
+            chain: vec![],
+        })
+    }
+
+    fn get_size_strategy() -> SizeExtraction {
+        SizeExtraction::FromStart
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_lowest_first() {
+        let mut free_space = FreeSpace::new();
+        free_space.release(&[7, 3, 12]);
+        assert_eq!(free_space.len(), 3);
+
+        assert_eq!(free_space.take(2), vec![3, 7]);
+        assert_eq!(free_space.take(5), vec![12]);
+        assert!(free_space.is_empty());
+        assert_eq!(free_space.take(1), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let mut free_space = FreeSpace::new();
+        free_space.release(&[4, 1, 900]);
+        let bytes = free_space.to_bytes_vec();
+        assert_eq!(bytes.len(), 8 + 3 * 8);
+        assert_eq!(free_space.stored_len(0), bytes.len());
+
+        let loaded = FreeSpace::from_bytes_vec(&bytes).unwrap();
+        assert_eq!(loaded.positions, free_space.positions);
+        assert!(loaded.contains(900));
+
+        let result = FreeSpace::from_bytes_vec(&bytes[..bytes.len() - 1]);
+        assert!(matches!(result, Err(FromBytesError::ReadLenError)));
+
+        let mut huge = bytes.clone();
+        huge[..8].copy_from_slice(&u64::MAX.to_bytes_vec());
+        assert!(matches!(FreeSpace::from_bytes_vec(&huge), Err(FromBytesError::ReadLenError)));
+    }
+}
@@ -0,0 +1,220 @@
+use std::{io::{Read, Seek, SeekFrom, Write}, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::storage::{block::{is_valid_block_size, DEFAULT_BLOCK_SIZE}, serialization::{FromBytes, FromBytesError, SizeExtraction, ToBytes}};
+
+pub const MAGIC: [u8; 8] = *b"FASTERDB";
+pub const FORMAT_VERSION: u32 = 5; // 2: chains link blocks by absolute position, 3: blocks start with a kind byte, 4: slotted pages,
+                                     // 5: every block ends with a checksum
+pub const HEADER_SIZE: usize = 64; // fields take 44 bytes, the rest is reserved
+const NO_ROOT: u64 = u64::MAX;
+
+// First bytes of every database file, blocks start right after it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub block_size: u32, // fixed when the file is created, every block of the file has this size
+    pub created_at: u64, // seconds since the unix epoch
+    pub index_root: Option<u64>,
+    pub free_space_root: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum HeaderError {
+    Io(std::io::Error),
+    ForeignFile, // not a fasterdb file at all
+    UnsupportedVersion { found: u32, supported: u32 },
+    InvalidBlockSize(u32),
+}
+
+impl From<std::io::Error> for HeaderError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl Default for FileHeader {
+    fn default() -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            block_size: DEFAULT_BLOCK_SIZE as u32,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            index_root: None,
+            free_space_root: None,
+        }
+    }
+}
+
+fn root_to_bytes(root: Option<u64>) -> Vec<u8> {
+    root.unwrap_or(NO_ROOT).to_bytes_vec()
+}
+
+fn root_from_bytes(bytes: &[u8]) -> Result<Option<u64>, FromBytesError> {
+    Ok(match u64::from_bytes_vec(bytes)? {
+        NO_ROOT => None,
+        root => Some(root),
+    })
+}
+
+impl FileHeader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_block_size(block_size: usize) -> Self {
+        Self { block_size: block_size as u32, ..Self::default() }
+    }
+
+    pub fn validate(&self) -> Result<(), HeaderError> {
+        if self.magic != MAGIC {
+            return Err(HeaderError::ForeignFile);
+        }
+        if self.version != FORMAT_VERSION { // older files link blocks differently, reading them would follow wrong links
+            return Err(HeaderError::UnsupportedVersion { found: self.version, supported: FORMAT_VERSION });
+        }
+        if !is_valid_block_size(self.block_size as usize) {
+            return Err(HeaderError::InvalidBlockSize(self.block_size));
+        }
+        Ok(())
+    }
+
+    // Reads and validates the header at the start of an existing file
+    pub fn read_from(file: &mut (impl Read + Seek)) -> Result<Self, HeaderError> {
+        let mut buf = [0u8; HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buf).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => HeaderError::ForeignFile,
+            _ => HeaderError::Io(e),
+        })?;
+
+        Self::decode(&buf)
+    }
+
+    // Parses header bytes that were already read, e.g. with a positional read
+    pub fn decode(bytes: &[u8]) -> Result<Self, HeaderError> {
+        let header = Self::from_bytes_vec(bytes).map_err(|_| HeaderError::ForeignFile)?;
+        header.validate()?;
+        Ok(header)
+    }
+
+    pub fn write_to(&self, file: &mut (impl Write + Seek)) -> Result<(), HeaderError> {
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.to_bytes_vec())?;
+        Ok(())
+    }
+
+    // Writes a fresh header into an empty file, or validates the one an existing file has.
+    // `block_size` only applies to a new file, an existing one keeps the size it was created with
+    pub fn init_or_validate(file: &mut (impl Read + Write + Seek), block_size: usize) -> Result<Self, HeaderError> {
+        if file.seek(SeekFrom::End(0))? == 0 {
+            let header = Self::with_block_size(block_size);
+            header.validate()?;
+            header.write_to(file)?;
+            return Ok(header);
+        }
+        Self::read_from(file)
+    }
+}
+
+impl ToBytes for FileHeader {
+    fn to_bytes_vec(&self) -> Vec<u8> {
+        let mut out_vec = self.magic.to_vec();
+        out_vec.extend(self.version.to_bytes_vec());
+        out_vec.extend(self.block_size.to_bytes_vec());
+        out_vec.extend(self.created_at.to_bytes_vec());
+        out_vec.extend(root_to_bytes(self.index_root));
+        out_vec.extend(root_to_bytes(self.free_space_root));
+        out_vec.resize(HEADER_SIZE, 0);
+        out_vec
+    }
+}
+
+impl FromBytes for FileHeader {
+    fn from_bytes_vec(bytes: &[u8]) -> Result<Self, FromBytesError> where Self:Sized {
+        if bytes.len() < HEADER_SIZE {
+            return Err(FromBytesError::ReadLenError);
+        }
+        Ok(Self {
+            magic: bytes[0..8].try_into().map_err(|_| FromBytesError::ReadLenError)?,
+            version: u32::from_bytes_vec(&bytes[8..12])?,
+            block_size: u32::from_bytes_vec(&bytes[12..16])?,
+            created_at: u64::from_bytes_vec(&bytes[16..24])?,
+            index_root: root_from_bytes(&bytes[24..32])?,
+            free_space_root: root_from_bytes(&bytes[32..40])?,
+        })
+    }
+
+    fn get_size_strategy() -> SizeExtraction {
+        SizeExtraction::Constant(HEADER_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_round_trip() {
+        let mut header = FileHeader::new();
+        header.index_root = Some(0);
+        header.free_space_root = Some(12);
+
+        let bytes = header.to_bytes_vec();
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert_eq!(&bytes[0..8], b"FASTERDB");
+        assert_eq!(&bytes[40..], [0u8; 24]);
+
+        let loaded = FileHeader::from_bytes_vec(&bytes).unwrap();
+        assert_eq!(loaded, header);
+        assert!(loaded.validate().is_ok());
+    }
+
+    #[test]
+    fn test_init_or_validate() {
+        let mut file = Cursor::new(vec![]);
+        let created = FileHeader::init_or_validate(&mut file, 256).unwrap();
+        assert_eq!(file.get_ref().len(), HEADER_SIZE);
+        assert_eq!(created.index_root, None);
+        assert_eq!(created.block_size, 256);
+
+        let opened = FileHeader::init_or_validate(&mut file, DEFAULT_BLOCK_SIZE).unwrap();
+        assert_eq!(opened, created);
+
+        let mut file = Cursor::new(vec![]);
+        let result = FileHeader::init_or_validate(&mut file, 16);
+        assert!(matches!(result, Err(HeaderError::InvalidBlockSize(16))));
+        assert!(file.get_ref().is_empty());
+    }
+
+    #[test]
+    fn test_reject_foreign_and_newer_files() {
+        let mut file = Cursor::new(vec![7u8; 100]);
+        assert!(matches!(FileHeader::read_from(&mut file), Err(HeaderError::ForeignFile)));
+
+        let mut file = Cursor::new(b"FASTER".to_vec());
+        assert!(matches!(FileHeader::read_from(&mut file), Err(HeaderError::ForeignFile)));
+
+        let mut header = FileHeader::new();
+        header.version = FORMAT_VERSION + 1;
+        let mut file = Cursor::new(header.to_bytes_vec());
+        assert!(matches!(
+            FileHeader::read_from(&mut file),
+            Err(HeaderError::UnsupportedVersion { found, supported: FORMAT_VERSION }) if found == FORMAT_VERSION + 1
+        ));
+
+        // older files may lack the checksums, reading them would report every block as corrupt
+        for version in 1..FORMAT_VERSION {
+            let mut header = FileHeader::new();
+            header.version = version;
+            let mut file = Cursor::new(header.to_bytes_vec());
+            assert!(matches!(FileHeader::read_from(&mut file), Err(HeaderError::UnsupportedVersion { found, .. }) if found == version));
+        }
+
+        let mut header = FileHeader::new();
+        header.block_size = 8;
+        let mut file = Cursor::new(header.to_bytes_vec());
+        assert!(matches!(FileHeader::read_from(&mut file), Err(HeaderError::InvalidBlockSize(8))));
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+
+use crate::storage::serialization::{FromBytes, FromBytesError, SizeExtraction, ToBytes};
+
+const ENTRY_SIZE: usize = 16; // document id + head position
+
+// Primary index, maps every document id to the first block of its chain
+#[derive(Debug, Clone)]
+pub struct Index {
+    entries: BTreeMap<u64, u64>,
+    next_id: u64,
+    chain: Vec<u64>, // blocks holding the index itself
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            next_id: 1,
+            chain: vec![],
+        }
+    }
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, id: u64) -> Option<u64> {
+        self.entries.get(&id).copied()
+    }
+
+    pub fn insert(&mut self, id: u64, head: u64) -> Option<u64> {
+        self.next_id = self.next_id.max(id.saturating_add(1)); // u64::MAX is a valid id, but leaves nothing after it
+        self.entries.insert(id, head)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<u64> {
+        self.entries.remove(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.entries.iter().map(|(id, head)| (*id, *head))
+    }
+
+    pub fn get_next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    // Carries the id counter over to a rebuilt index, it only ever moves forward
+    pub fn set_next_id(&mut self, next_id: u64) {
+        self.next_id = self.next_id.max(next_id);
+    }
+
+    pub fn get_chain(&self) -> &[u64] {
+        &self.chain
+    }
+
+    pub fn set_chain(&mut self, chain: Vec<u64>) {
+        self.chain = chain;
+    }
+}
+
+impl ToBytes for Index {
+    fn to_bytes_vec(&self) -> Vec<u8> {
+        let mut out_vec = self.next_id.to_bytes_vec();
+        out_vec.extend(self.entries.len().to_bytes_vec());
+        for (id, head) in &self.entries {
+            out_vec.extend(id.to_bytes_vec());
+            out_vec.extend(head.to_bytes_vec());
+        }
+        out_vec
+    }
+}
+
+impl FromBytes for Index {
+    fn from_bytes_vec(bytes: &[u8]) -> Result<Self, FromBytesError> where Self:Sized {
+        let len_size = std::mem::size_of::<usize>();
+        if bytes.len() < 8 + len_size {
+            return Err(FromBytesError::ReadLenError);
+        }
+        let next_id = u64::from_bytes_vec(&bytes[..8])?;
+        let count = usize::from_bytes_vec(&bytes[8..8 + len_size])?;
+        let entries = &bytes[8 + len_size..];
+        if count.checked_mul(ENTRY_SIZE) != Some(entries.len()) { // the count comes from the file, it may be anything
+            return Err(FromBytesError::ReadLenError);
+        }
+
+        let mut index = Self { next_id, ..Self::default() };
+        for entry in entries.chunks(ENTRY_SIZE) {
+            index.entries.insert(u64::from_bytes_vec(&entry[..8])?, u64::from_bytes_vec(&entry[8..])?);
+        }
+        Ok(index)
+    }
+
+    fn get_size_strategy() -> SizeExtraction {
+        SizeExtraction::FromStart
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut index = Index::new();
+        assert_eq!(index.get_next_id(), 1);
+        assert!(index.is_empty());
+
+        assert_eq!(index.insert(3, 40), None);
+        assert_eq!(index.insert(1, 10), None);
+        assert_eq!(index.get_next_id(), 4);
+        assert_eq!(index.insert(3, 41), Some(40));
+        assert_eq!(index.get(3), Some(41));
+
+        assert_eq!(index.remove(3), Some(41));
+        assert_eq!(index.remove(3), None);
+        assert_eq!(index.get_next_id(), 4); // ids are never handed out twice
+        assert_eq!(index.iter().collect::<Vec<_>>(), vec![(1, 10)]);
+        assert_eq!(index.len(), 1);
+
+        index.insert(u64::MAX, 5);
+        assert_eq!(index.get(u64::MAX), Some(5));
+        assert_eq!(index.get_next_id(), u64::MAX);
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let mut index = Index::new();
+        index.insert(2, 7);
+        index.insert(9, 1);
+        index.remove(9);
+
+        let bytes = index.to_bytes_vec();
+        assert_eq!(bytes.len(), 8 + 8 + ENTRY_SIZE);
+
+        let loaded = Index::from_bytes_vec(&bytes).unwrap();
+        assert_eq!(loaded.get(2), Some(7));
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.get_next_id(), 10);
+
+        let result = Index::from_bytes_vec(&bytes[..bytes.len() - 3]);
+        assert!(matches!(result, Err(FromBytesError::ReadLenError)));
+
+        let mut huge = bytes.clone();
+        huge[8..16].copy_from_slice(&u64::MAX.to_bytes_vec());
+        assert!(matches!(Index::from_bytes_vec(&huge), Err(FromBytesError::ReadLenError)));
+    }
+}
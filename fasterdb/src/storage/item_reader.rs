@@ -0,0 +1,255 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::storage::{block::{Block, BlockKind, BLOCK_OVERHEAD}, block_stroage::{BlockSeek, Reader, ReaderError, ITEM_LENGTH_SIZE}, serialization::{FromBytes, FromBytesError}};
+
+// Streams the item stored in a chain, holding one block at a time however long the item is. Moving
+// back walks the chain again from its head, so nothing grows with the chain's length
+pub struct ItemReader<'a> {
+    reader: &'a Reader,
+    head: u64,
+    len: u64,
+    limit: u64,    // most blocks the chain may have, see Reader::chain_limit
+    position: u64, // next byte of the item to read
+    walk: Walk,
+    checked: bool, // the chain was followed to its end once, so it doesn't loop back
+}
+
+// A walk along the chain, at its `index`th block. A chain looping back comes around to `marker`
+// again, which moves ahead each time the walk has taken twice as many steps as the time before
+#[derive(Clone)]
+struct Walk {
+    index: u64,
+    block: Block,
+    marker: u64,
+    lap: u64,
+}
+
+fn io_error(err: ReaderError) -> std::io::Error {
+    match err {
+        ReaderError::Io(err) => err,
+        err => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{err:?}")),
+    }
+}
+
+impl Walk {
+    fn start(reader: &Reader, head: u64) -> Result<Self, ReaderError> {
+        Ok(Self { index: 0, block: reader.read_block(BlockSeek::Start(head))?, marker: head, lap: 1 })
+    }
+
+    // Moves on to the next block, false at the end of the chain
+    fn step(&mut self, reader: &Reader, head: u64, limit: u64) -> Result<bool, ReaderError> {
+        let Some(next) = self.block.get_next_block() else {
+            return Ok(false);
+        };
+        if next == self.marker {
+            return Err(ReaderError::ChainCycle { head, position: next });
+        }
+        if self.index + 1 >= limit {
+            return Err(ReaderError::ChainTooLong { head, limit });
+        }
+        self.index += 1;
+        if self.index == self.lap {
+            self.marker = next;
+            self.lap *= 2;
+        }
+        self.block = reader.read_block(BlockSeek::Start(next))?;
+        Ok(true)
+    }
+}
+
+impl<'a> ItemReader<'a> {
+    pub fn new(reader: &'a Reader, head: u64) -> Result<Self, ReaderError> {
+        let walk = Walk::start(reader, head)?;
+        // a page's slots or the middle of a chain would be taken for an item's length
+        if !matches!(walk.block.get_kind(), BlockKind::Document | BlockKind::Index | BlockKind::FreeSpace) {
+            return Err(ReaderError::FromReaderError(format!("Block {head} is not the first block of an item")));
+        }
+        let len = u64::from_bytes_vec(&walk.block.data[..ITEM_LENGTH_SIZE])?;
+
+        // a length no chain of this file could hold is taken for a corrupt one, like a chain that's too long
+        let limit = reader.chain_limit()?;
+        if (ITEM_LENGTH_SIZE as u64).saturating_add(len).div_ceil(Self::data_size(reader) as u64) > limit {
+            return Err(ReaderError::ChainTooLong { head, limit });
+        }
+        Ok(Self { reader, head, len, limit, position: 0, walk, checked: false })
+    }
+
+    fn data_size(reader: &Reader) -> usize {
+        reader.get_block_size() - BLOCK_OVERHEAD
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Makes the walk's block the one at `index` in the chain, from the head again when it's behind
+    fn move_to(&mut self, index: u64) -> Result<(), ReaderError> {
+        if index < self.walk.index {
+            self.walk = Walk::start(self.reader, self.head)?;
+        }
+        while self.walk.index < index {
+            if !self.walk.step(self.reader, self.head, self.limit)? {
+                return Err(ReaderError::FromBytesError(FromBytesError::ReadLenError)); // the chain ends before the item does
+            }
+        }
+
+        // the item ends in this block, but a chain looping back would go on with the same blocks
+        let last = (ITEM_LENGTH_SIZE as u64 + self.len).div_ceil(Self::data_size(self.reader) as u64) - 1;
+        if !self.checked && index == last {
+            let mut tail = self.walk.clone();
+            while tail.step(self.reader, self.head, self.limit)? {}
+            self.checked = true;
+        }
+        Ok(())
+    }
+}
+
+impl Read for ItemReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let data_size = Self::data_size(self.reader) as u64;
+        let offset = ITEM_LENGTH_SIZE as u64 + self.position; // the length comes first in the chain
+        self.move_to(offset / data_size).map_err(io_error)?;
+
+        let start = (offset % data_size) as usize;
+        let count = buf.len()
+            .min(data_size as usize - start)
+            .min((self.len - self.position) as usize);
+        buf[..count].copy_from_slice(&self.walk.block.data[start..start + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for ItemReader<'_> {
+    // Only moves the position, the blocks are found on the next read. Past the end reads return nothing
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(position) = position else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the item"));
+        };
+        self.position = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{block::DEFAULT_DATA_SIZE, block_stroage::{BlockStorage, OpenMode, StorageConfig, StorageOption, Writer}, memory::MemoryBuffer};
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_stream_item() {
+        let data = payload(DEFAULT_DATA_SIZE * 4 + 17);
+        let storage = BlockStorage::open(StorageOption::Memory(MemoryBuffer::new()), OpenMode::CreateNew).unwrap();
+        let head = storage.write_full_item(1, &data).unwrap();
+
+        let mut item = storage.item_reader(head.clone()).unwrap();
+        assert_eq!(item.len(), data.len() as u64);
+        let mut out = vec![];
+        std::io::copy(&mut item, &mut out).unwrap();
+        assert_eq!(out, data);
+
+        // reads never cross a block, and odd sized ones still add up to the item
+        let mut item = storage.item_reader(head.clone()).unwrap();
+        let mut out = vec![];
+        let mut buf = [0u8; 333];
+        loop {
+            match item.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(out, data);
+
+        let head = storage.write_full_item(2, b"").unwrap();
+        let mut item = storage.item_reader(head).unwrap();
+        assert!(item.is_empty());
+        assert_eq!(item.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_seek_item() {
+        let data = payload(DEFAULT_DATA_SIZE * 3);
+        let storage = BlockStorage::open(StorageOption::Memory(MemoryBuffer::new()), OpenMode::CreateNew).unwrap();
+        let mut item = storage.item_reader(storage.write_full_item(1, &data).unwrap()).unwrap();
+        let mut buf = [0u8; 10];
+
+        assert_eq!(item.seek(SeekFrom::Start(DEFAULT_DATA_SIZE as u64 * 2 + 5)).unwrap(), DEFAULT_DATA_SIZE as u64 * 2 + 5);
+        item.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[DEFAULT_DATA_SIZE * 2 + 5..DEFAULT_DATA_SIZE * 2 + 15]);
+
+        // back to a block already passed, then relative to the end
+        item.seek(SeekFrom::Current(-(DEFAULT_DATA_SIZE as i64) * 2)).unwrap();
+        item.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[15..25]);
+        item.seek(SeekFrom::End(-4)).unwrap();
+        assert_eq!(item.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &data[data.len() - 4..]);
+
+        assert_eq!(item.seek(SeekFrom::End(100)).unwrap(), data.len() as u64 + 100);
+        assert_eq!(item.read(&mut buf).unwrap(), 0);
+        let err = item.seek(SeekFrom::Current(-(data.len() as i64) - 200)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_stream_broken_chain() {
+        let buffer = MemoryBuffer::new();
+        let writer = Writer::new(StorageOption::Memory(buffer.clone())).unwrap();
+        writer.write_full_item(1, &payload(DEFAULT_DATA_SIZE * 5)).unwrap();
+
+        // the fourth block links back to the second
+        let reader = Reader::new(StorageOption::Memory(buffer.clone())).unwrap();
+        let mut block = reader.read_block(BlockSeek::Start(3)).unwrap();
+        block.set_next_block(1);
+        writer.write(block, BlockSeek::Start(3)).unwrap();
+        let mut out = vec![];
+        let err = reader.item_reader(BlockSeek::Start(0)).unwrap().read_to_end(&mut out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // a loop that only starts past the item's end still gives the chain away
+        let mut block = reader.read_block(BlockSeek::Start(3)).unwrap();
+        block.set_next_block(4);
+        writer.write(block, BlockSeek::Start(3)).unwrap();
+        let mut block = reader.read_block(BlockSeek::Start(5)).unwrap();
+        block.set_next_block(5);
+        writer.write(block, BlockSeek::Start(5)).unwrap();
+        let mut item = reader.item_reader(BlockSeek::Start(0)).unwrap();
+        item.seek(SeekFrom::End(-1)).unwrap();
+        assert!(item.read(&mut [0u8; 1]).is_err());
+
+        // a length longer than any item the file allows
+        drop(writer);
+        let config = StorageConfig { max_item_size: DEFAULT_DATA_SIZE, ..StorageConfig::default() };
+        let storage = BlockStorage::open_with_config(StorageOption::Memory(buffer), OpenMode::OpenExisting, config).unwrap();
+        assert!(matches!(storage.item_reader(BlockSeek::Start(0)), Err(ReaderError::ChainTooLong { head: 0, limit: 2 })));
+    }
+
+    #[test]
+    fn test_stream_needs_a_chain_head() {
+        let storage = BlockStorage::open(StorageOption::Memory(MemoryBuffer::new()), OpenMode::CreateNew).unwrap();
+        let page = storage.write_document(1, b"small").unwrap();
+        let err = storage.item_reader(BlockSeek::Start(page)).err().unwrap();
+        assert!(matches!(err, ReaderError::FromReaderError(_)));
+
+        let head = storage.write_document(2, &payload(DEFAULT_DATA_SIZE * 2)).unwrap();
+        let overflow = storage.chain_positions(head).unwrap()[1];
+        assert!(matches!(storage.item_reader(BlockSeek::Start(overflow)), Err(ReaderError::FromReaderError(_))));
+        assert_eq!(storage.item_reader(BlockSeek::Start(head)).unwrap().len(), DEFAULT_DATA_SIZE as u64 * 2);
+    }
+}
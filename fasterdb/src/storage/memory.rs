@@ -0,0 +1,164 @@
+use std::{io::{Read, Seek, SeekFrom, Write}, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+
+use crate::storage::block_stroage::{ReadAt, WriteSeek};
+
+// Growable in-memory file, every clone shares the same bytes
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBuffer {
+    bytes: Arc<RwLock<Vec<u8>>>,
+}
+
+// Read/write cursor over a memory buffer, the in-memory counterpart of an open file
+#[derive(Debug)]
+pub struct MemoryCursor {
+    buffer: MemoryBuffer,
+    position: u64,
+}
+
+fn lock_error<E: std::fmt::Debug>(err: E) -> std::io::Error {
+    std::io::Error::other(format!("Failed to acquire memory buffer lock {:?}", err))
+}
+
+impl MemoryBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read_bytes(&self) -> std::io::Result<RwLockReadGuard<'_, Vec<u8>>> {
+        self.bytes.read().map_err(lock_error)
+    }
+
+    fn write_bytes(&self) -> std::io::Result<RwLockWriteGuard<'_, Vec<u8>>> {
+        self.bytes.write().map_err(lock_error)
+    }
+
+    pub fn len(&self) -> std::io::Result<u64> {
+        Ok(self.read_bytes()?.len() as u64)
+    }
+
+    pub fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn cursor(&self) -> MemoryCursor {
+        MemoryCursor { buffer: self.clone(), position: 0 }
+    }
+
+    pub fn to_vec(&self) -> std::io::Result<Vec<u8>> {
+        Ok(self.read_bytes()?.clone())
+    }
+
+    // Swaps in new contents for every clone at once
+    pub fn set_contents(&self, bytes: Vec<u8>) -> std::io::Result<()> {
+        *self.write_bytes()? = bytes;
+        Ok(())
+    }
+}
+
+impl ReadAt for MemoryBuffer {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.visit_at(offset, buf.len(), &mut |bytes| buf.copy_from_slice(bytes))
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        self.len()
+    }
+
+    fn visit_at(&self, offset: u64, len: usize, visit: &mut dyn FnMut(&[u8])) -> std::io::Result<()> {
+        let bytes = self.read_bytes()?;
+        let start = offset as usize;
+        let Some(source) = bytes.get(start..start + len) else {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        };
+        visit(source);
+        Ok(())
+    }
+}
+
+impl Read for MemoryCursor {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes = self.buffer.read_bytes()?;
+        let start = (self.position as usize).min(bytes.len());
+        let read_len = buf.len().min(bytes.len() - start);
+        buf[..read_len].copy_from_slice(&bytes[start..start + read_len]);
+        self.position += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl Write for MemoryCursor {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut bytes = self.buffer.write_bytes()?;
+        let start = self.position as usize;
+        if bytes.len() < start + buf.len() {
+            bytes.resize(start + buf.len(), 0); // writing past the end fills the gap with zeros, like a file
+        }
+        bytes[start..start + buf.len()].copy_from_slice(buf);
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteSeek for MemoryCursor {}
+
+impl Seek for MemoryCursor {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.buffer.len()?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the buffer")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_read_write_seek() {
+        let buffer = MemoryBuffer::new();
+        let mut cursor = buffer.cursor();
+        cursor.write_all(b"hello").unwrap();
+        cursor.seek(SeekFrom::Start(8)).unwrap();
+        cursor.write_all(b"world").unwrap();
+        assert_eq!(buffer.to_vec().unwrap(), b"hello\0\0\0world");
+
+        // a second cursor shares the bytes but not the position
+        let mut other = buffer.cursor();
+        let mut out = [0u8; 5];
+        other.read_exact(&mut out).unwrap();
+        assert_eq!(&out, b"hello");
+        assert_eq!(other.seek(SeekFrom::End(-5)).unwrap(), 8);
+        assert!(other.seek(SeekFrom::Current(-20)).is_err());
+
+        let mut rest = vec![];
+        other.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"world");
+    }
+
+    #[test]
+    fn test_read_exact_at() {
+        let buffer = MemoryBuffer::new();
+        buffer.cursor().write_all(b"0123456789").unwrap();
+
+        let mut out = [0u8; 3];
+        buffer.read_exact_at(&mut out, 4).unwrap();
+        assert_eq!(&out, b"456");
+        assert_eq!(buffer.size().unwrap(), 10);
+
+        let result = buffer.read_exact_at(&mut out, 8);
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,270 @@
+use std::{fs::File, io::{Read, Seek, SeekFrom, Write}, sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock, RwLockReadGuard}};
+
+use memmap2::{Mmap, MmapMut, MmapOptions};
+
+use crate::storage::block_stroage::{ReadAt, WriteSeek};
+
+fn lock_error<E: std::fmt::Debug>(err: E) -> std::io::Error {
+    std::io::Error::other(format!("Failed to acquire mapping lock {:?}", err))
+}
+
+// Read side of a memory mapped file, remapped whenever a read runs past the end of the current mapping
+pub struct MmapReader {
+    file: File,
+    map: RwLock<Option<Mmap>>, // None while the file is empty, an empty file can't be mapped
+    written: Option<Arc<AtomicU64>>, // bytes a writer of this process wrote, its file may be longer
+}
+
+impl MmapReader {
+    pub fn new(file: File) -> std::io::Result<Self> {
+        Self::with_written(file, None)
+    }
+
+    // A reader next to an MmapWriter, which grows the file ahead of what it wrote
+    pub fn following(file: File, written: Arc<AtomicU64>) -> std::io::Result<Self> {
+        Self::with_written(file, Some(written))
+    }
+
+    fn with_written(file: File, written: Option<Arc<AtomicU64>>) -> std::io::Result<Self> {
+        let reader = Self { file, map: RwLock::new(None), written };
+        reader.remap()?;
+        Ok(reader)
+    }
+
+    fn remap(&self) -> std::io::Result<()> {
+        let mut map = self.map.write().map_err(lock_error)?;
+        *map = match self.file.metadata()?.len() {
+            0 => None,
+            // SAFETY: the file belongs to this database. The writer only truncates it down to what it
+            // wrote, and the bytes past that are never read
+            _ => Some(unsafe { Mmap::map(&self.file)? }),
+        };
+        Ok(())
+    }
+
+    fn mapped_len(map: &RwLockReadGuard<'_, Option<Mmap>>) -> u64 {
+        map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+}
+
+impl ReadAt for MmapReader {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.visit_at(offset, buf.len(), &mut |bytes| buf.copy_from_slice(bytes))
+    }
+
+    // Hands out the mapped bytes themselves, without copying them into a buffer first
+    fn visit_at(&self, offset: u64, len: usize, visit: &mut dyn FnMut(&[u8])) -> std::io::Result<()> {
+        let end = offset + len as u64;
+        if self.written.as_ref().is_some_and(|written| written.load(Ordering::Acquire) < end) {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if Self::mapped_len(&self.map.read().map_err(lock_error)?) < end {
+            self.remap()?; // the writer appended blocks since the last mapping
+        }
+
+        let map = self.map.read().map_err(lock_error)?;
+        if Self::mapped_len(&map) < end {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let map = map.as_ref().expect("a non empty mapping exists");
+        visit(&map[offset as usize..end as usize]);
+        Ok(())
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        match &self.written {
+            Some(written) => Ok(written.load(Ordering::Acquire)),
+            None => Ok(self.file.metadata()?.len()),
+        }
+    }
+}
+
+// Write side of a memory mapped file. The file and its mapping grow ahead of the appends in chunks
+// that double, so appending blocks one by one neither resizes nor remaps each time. Flushing trims
+// the file back to what was written
+pub struct MmapWriter {
+    file: File,
+    map: Option<MmapMut>,
+    len: u64,      // bytes written, the file and the mapping may reach past them
+    file_len: u64,
+    written: Arc<AtomicU64>, // `len` for readers of this process
+    position: u64,
+}
+
+const MIN_MAPPING: u64 = 64 * 1024;
+
+impl MmapWriter {
+    pub fn new(file: File) -> std::io::Result<Self> {
+        let len = file.metadata()?.len();
+        let mut writer = Self { file, map: None, len, file_len: len, written: Arc::new(AtomicU64::new(len)), position: 0 };
+        writer.remap(len)?;
+        Ok(writer)
+    }
+
+    pub fn written(&self) -> Arc<AtomicU64> {
+        self.written.clone()
+    }
+
+    fn remap(&mut self, capacity: u64) -> std::io::Result<()> {
+        self.flush()?;
+        self.map = match capacity {
+            0 => None,
+            // SAFETY: see MmapReader::remap. Only the bytes below `file_len`, which the file holds,
+            // are ever touched, the pages past the end of the file are left alone
+            _ => Some(unsafe { MmapOptions::new().len(capacity as usize).map_mut(&self.file)? }),
+        };
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+
+    fn grow_to(&mut self, len: u64) -> std::io::Result<()> {
+        if self.file_len < len {
+            if self.capacity() < len {
+                self.remap(len.max(self.capacity() * 2).max(MIN_MAPPING))?;
+            }
+            self.file.set_len(self.capacity())?;
+            self.file_len = self.capacity();
+        }
+        self.len = len;
+        self.written.store(len, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Read for MmapWriter {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = self.position.min(self.len) as usize;
+        let Some(map) = &self.map else {
+            return Ok(0);
+        };
+        let read_len = buf.len().min(self.len as usize - start);
+        buf[..read_len].copy_from_slice(&map[start..start + read_len]);
+        self.position += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl Write for MmapWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let end = self.position + buf.len() as u64;
+        if self.len < end {
+            self.grow_to(end)?;
+        }
+        let start = self.position as usize;
+        let map = self.map.as_mut().expect("the mapping was just grown");
+        map[start..start + buf.len()].copy_from_slice(buf);
+        self.position = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(map) = &self.map && self.len > 0 {
+            map.flush_range(0, self.len as usize)?;
+        }
+        if self.file_len > self.len {
+            self.file.set_len(self.len)?;
+            self.file_len = self.len;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MmapWriter {
+    fn drop(&mut self) {
+        let _ = self.flush(); // the file keeps no more than was written, there's no one to tell it failed
+    }
+}
+
+impl WriteSeek for MmapWriter {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()?; // msync covers the mapped bytes, the file length may still need its own sync
+        self.file.sync_data()
+    }
+}
+
+impl Seek for MmapWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the mapping")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn test_reader_follows_writer_growth() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path();
+        let mut writer = MmapWriter::new(OpenOptions::new().read(true).write(true).open(path).unwrap()).unwrap();
+        let reader = MmapReader::new(File::open(path).unwrap()).unwrap();
+
+        let mut out = [0u8; 4];
+        assert_eq!(reader.read_exact_at(&mut out, 0).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+
+        writer.write_all(b"abcd").unwrap();
+        reader.read_exact_at(&mut out, 0).unwrap();
+        assert_eq!(&out, b"abcd");
+
+        writer.seek(SeekFrom::Start(10)).unwrap();
+        writer.write_all(b"wxyz").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(reader.size().unwrap(), 14);
+        reader.visit_at(10, 4, &mut |bytes| assert_eq!(bytes, b"wxyz")).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"abcd\0\0\0\0\0\0wxyz");
+
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        let mut all = vec![];
+        writer.read_to_end(&mut all).unwrap();
+        assert_eq!(all.len(), 14);
+    }
+
+    #[test]
+    fn test_writer_maps_ahead() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path();
+        let mut writer = MmapWriter::new(OpenOptions::new().read(true).write(true).open(path).unwrap()).unwrap();
+
+        // appends within the mapping leave it and the file's length in place
+        writer.write_all(&[1u8; 100]).unwrap();
+        assert_eq!(writer.capacity(), MIN_MAPPING);
+        let mapped = writer.map.as_ref().unwrap().as_ptr();
+        for _ in 0..100 {
+            writer.write_all(&[2u8; 100]).unwrap();
+        }
+        assert_eq!(writer.map.as_ref().unwrap().as_ptr(), mapped);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), MIN_MAPPING);
+        assert_eq!(writer.seek(SeekFrom::End(0)).unwrap(), 10_100);
+
+        // a reader next to the writer ends where the writes do, and the flushed file does too
+        let reader = MmapReader::following(File::open(path).unwrap(), writer.written()).unwrap();
+        assert_eq!(reader.size().unwrap(), 10_100);
+        assert_eq!(reader.read_exact_at(&mut [0u8; 1], 10_100).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        writer.flush().unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 10_100);
+
+        writer.write_all(&vec![3u8; MIN_MAPPING as usize]).unwrap();
+        assert_eq!(writer.capacity(), MIN_MAPPING * 2);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), MIN_MAPPING * 2);
+        drop(writer);
+        let written = std::fs::read(path).unwrap();
+        assert_eq!(written.len(), 10_100 + MIN_MAPPING as usize);
+        assert!(written[10_100..].iter().all(|b| *b == 3));
+        assert_eq!(reader.size().unwrap(), written.len() as u64);
+    }
+}
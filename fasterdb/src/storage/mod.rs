@@ -1,3 +1,16 @@
-mod block;
-mod serialization;
-mod block_stroage; // יושם בעתיד
\ No newline at end of file
+pub mod block;
+pub mod cache;
+pub mod checksum;
+pub mod serialization;
+pub mod block_stroage;
+pub mod free_space;
+pub mod index;
+pub mod item_reader;
+pub mod header;
+pub mod memory;
+pub mod mmap;
+pub mod rebuild;
+pub mod scan;
+pub mod slotted;
+pub mod verify;
+pub mod wal;
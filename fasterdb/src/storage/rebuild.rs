@@ -0,0 +1,235 @@
+use std::{collections::HashSet, path::Path};
+
+use crate::storage::{block::BlockKind, block_stroage::{BlockSeek, BlockStorage, OpenMode, ReaderError, StorageConfig, StorageError, StorageOption}, free_space::FreeSpace, index::Index, serialization::{FromBytes, ToBytes}, slotted::SlottedPage};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RebuildReport {
+    pub documents: usize,
+    pub next_id: u64,
+    pub free_blocks: usize,
+    pub skipped: Vec<u64>,    // blocks that looked like documents but couldn't be read, freed with the rest
+    pub duplicates: Vec<u64>, // ids found more than once, the copy nearest the start of the file is kept
+}
+
+// Recovers a file whose index is lost or broken. Every block is scanned, document chains and pages
+// are found by their kind and the ids they carry, and a fresh index and free space list replace the
+// old ones. Blocks no document owns, the old index among them, are freed
+pub fn rebuild_index(path: &Path) -> Result<RebuildReport, StorageError> {
+    rebuild_index_storage(StorageOption::File(path.to_path_buf()))
+}
+
+pub fn rebuild_index_storage(stored_in: StorageOption) -> Result<RebuildReport, StorageError> {
+    // the free space list may be what's broken, the rebuild lays down a new one anyway
+    let config = StorageConfig { load_free_space: false, ..StorageConfig::default() };
+    let storage = BlockStorage::open_with_config(stored_in, OpenMode::OpenExisting, config)?;
+    storage.begin()?;
+    match rebuild(&storage) {
+        Ok(report) => {
+            storage.commit()?;
+            Ok(report)
+        },
+        Err(err) => match storage.rollback() {
+            Ok(()) => Err(err),
+            Err(rollback) => Err(StorageError::RollbackFailed { cause: Box::new(err), rollback }),
+        },
+    }
+}
+
+// The old roots stay in the header until the transaction writing the new ones commits, so a failed
+// or interrupted rebuild leaves the file as it found it
+fn rebuild(storage: &BlockStorage) -> Result<RebuildReport, StorageError> {
+    let mut header = storage.read_header()?;
+    // ids of deleted documents are never handed out again, as long as the old index can still tell
+    let next_id = header.index_root
+        .and_then(|root| storage.read_full_item(BlockSeek::Start(root)).ok())
+        .and_then(|bytes| Index::from_bytes_vec(&bytes).ok())
+        .map_or(1, |index| index.get_next_id());
+
+    let mut kinds = vec![];
+    for found in storage.scan()? {
+        match found {
+            Ok((_, block)) => kinds.push(Some(block.get_kind())),
+            Err(ReaderError::Corrupted { .. } | ReaderError::FromBytesError(_)) => kinds.push(None),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let mut report = RebuildReport::default();
+    let mut index = Index::new();
+    // the old list's blocks hold the new one when they're intact, otherwise they're freed with the rest
+    let free_space_chain = header.free_space_root
+        .and_then(|root| item_chain(storage, &kinds, BlockKind::FreeSpace, root))
+        .unwrap_or_default();
+    let mut owned: HashSet<u64> = free_space_chain.iter().copied().collect();
+    for (position, kind) in kinds.iter().enumerate() {
+        let position = position as u64;
+        let ids = match kind {
+            Some(BlockKind::Document) => match item_chain(storage, &kinds, BlockKind::Document, position) {
+                Some(chain) if chain.iter().all(|position| !owned.contains(position)) => {
+                    owned.extend(chain);
+                    vec![storage.read_block(BlockSeek::Start(position))?.get_id()]
+                },
+                _ => {
+                    report.skipped.push(position);
+                    continue;
+                },
+            },
+            Some(BlockKind::Slotted) => match SlottedPage::from_bytes_vec(&storage.read_block(BlockSeek::Start(position))?.data) {
+                Ok(page) => {
+                    owned.insert(position);
+                    page.ids().collect()
+                },
+                Err(_) => {
+                    report.skipped.push(position);
+                    continue;
+                },
+            },
+            None => {
+                report.skipped.push(position);
+                continue;
+            },
+            _ => continue,
+        };
+        for id in ids {
+            match index.get(id) {
+                Some(_) => report.duplicates.push(id),
+                None => { index.insert(id, position); },
+            }
+        }
+    }
+
+    // free blocks only go back on the list, anything else nobody owns is zeroed on the way
+    let mut free_space = FreeSpace::new();
+    free_space.set_chain(free_space_chain);
+    let mut release = vec![];
+    for (position, kind) in kinds.iter().enumerate() {
+        let position = position as u64;
+        match kind {
+            _ if owned.contains(&position) => {},
+            Some(BlockKind::Free) => free_space.release(&[position]),
+            _ => release.push(position),
+        }
+    }
+    let BlockSeek::Start(free_space_root) = storage.rebuild_free_space(free_space, &release)? else {
+        unreachable!("rebuild_free_space always returns an absolute position");
+    };
+
+    index.set_next_id(next_id);
+    let BlockSeek::Start(index_root) = storage.write_item(BlockKind::Index, 0, &index.to_bytes_vec())? else {
+        unreachable!("write_item always returns an absolute position");
+    };
+    header.index_root = Some(index_root);
+    header.free_space_root = Some(free_space_root);
+    storage.write_header(&header)?;
+
+    report.documents = index.len();
+    report.next_id = index.get_next_id();
+    report.free_blocks = storage.free_block_count()?;
+    Ok(report)
+}
+
+// Blocks of the chain at `head` when the whole chain is intact, starts with a `kind` block, goes on
+// with continuation blocks and holds a readable item
+fn item_chain(storage: &BlockStorage, kinds: &[Option<BlockKind>], kind: BlockKind, head: u64) -> Option<Vec<u64>> {
+    let chain = storage.chain_positions(head).ok()?;
+    let intact = chain.iter().enumerate().all(|(i, position)| {
+        kinds.get(*position as usize) == Some(&Some(if i == 0 { kind } else { BlockKind::Overflow }))
+    });
+    if !intact {
+        return None;
+    }
+    storage.read_full_item(BlockSeek::Start(head)).ok()?;
+    Some(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collection::Collection, storage::{block::{DEFAULT_BLOCK_SIZE, DEFAULT_DATA_SIZE}, block_stroage::Reader, header::{FileHeader, HEADER_SIZE}, memory::MemoryBuffer, verify::verify_storage}};
+
+    // A three block document, two small ones sharing a page, and a deleted one
+    fn setup() -> MemoryBuffer {
+        let buffer = MemoryBuffer::new();
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew).unwrap();
+        collection.write("x".repeat(DEFAULT_DATA_SIZE * 2 + 5)).unwrap();
+        collection.write(String::from("small")).unwrap();
+        collection.write(String::from("smaller")).unwrap();
+        let gone = collection.write(String::from("gone")).unwrap();
+        collection.delete(gone).unwrap();
+        buffer
+    }
+
+    fn root_block(buffer: &MemoryBuffer, root: impl Fn(&FileHeader) -> Option<u64>) -> usize {
+        let header = Reader::new(StorageOption::Memory(buffer.clone())).unwrap().read_header().unwrap();
+        HEADER_SIZE + root(&header).unwrap() as usize * DEFAULT_BLOCK_SIZE
+    }
+
+    fn check_rebuilt(buffer: &MemoryBuffer, report: &RebuildReport) {
+        assert_eq!(report.documents, 3);
+        assert_eq!(report.next_id, 5);
+        assert!(report.duplicates.is_empty());
+
+        let verified = verify_storage(StorageOption::Memory(buffer.clone())).unwrap();
+        assert!(verified.is_ok(), "{:?}", verified.problems);
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::OpenExisting).unwrap();
+        assert_eq!(collection.len(), 3);
+        assert_eq!(collection.read(1).unwrap().map(String::len), Some(DEFAULT_DATA_SIZE * 2 + 5));
+        assert_eq!(collection.read(3).unwrap(), Some(&String::from("smaller")));
+        assert_eq!(collection.write(String::from("next")).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_rebuild_lost_index() {
+        let buffer = setup();
+        let mut bytes = buffer.to_vec().unwrap();
+        let index_at = root_block(&buffer, |header| header.index_root);
+        bytes[index_at + 30] ^= 1;
+        buffer.set_contents(bytes).unwrap();
+        assert!(Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::OpenExisting).is_err());
+
+        // the old index is unreadable, so the id counter comes from the documents found
+        let report = rebuild_index_storage(StorageOption::Memory(buffer.clone())).unwrap();
+        assert_eq!(report.skipped, vec![((index_at - HEADER_SIZE) / DEFAULT_BLOCK_SIZE) as u64]);
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::OpenExisting).unwrap();
+        assert_eq!(collection.get_next_id(), 4);
+        assert_eq!(collection.write(String::from("reused id")).unwrap(), 4);
+        collection.delete(4).unwrap();
+        drop(collection);
+
+        let report = rebuild_index_storage(StorageOption::Memory(buffer.clone())).unwrap();
+        check_rebuilt(&buffer, &report);
+    }
+
+    #[test]
+    fn test_rebuild_lost_free_space() {
+        let buffer = setup();
+        let mut bytes = buffer.to_vec().unwrap();
+        bytes[root_block(&buffer, |header| header.free_space_root) + 30] ^= 1;
+        buffer.set_contents(bytes).unwrap();
+        assert!(BlockStorage::open(StorageOption::Memory(buffer.clone()), OpenMode::OpenExisting).is_err());
+
+        let report = rebuild_index_storage(StorageOption::Memory(buffer.clone())).unwrap();
+        check_rebuilt(&buffer, &report);
+        assert!(report.free_blocks > 0);
+    }
+
+    #[test]
+    fn test_interrupted_rebuild_keeps_the_old_roots() {
+        let buffer = setup();
+        let before = Reader::new(StorageOption::Memory(buffer.clone())).unwrap().read_header().unwrap();
+
+        // the process dies with the rebuild written but not committed
+        let config = StorageConfig { load_free_space: false, ..StorageConfig::default() };
+        let storage = BlockStorage::open_with_config(StorageOption::Memory(buffer.clone()), OpenMode::OpenExisting, config).unwrap();
+        storage.begin().unwrap();
+        rebuild(&storage).unwrap();
+        drop(storage);
+
+        let after = Reader::new(StorageOption::Memory(buffer.clone())).unwrap().read_header().unwrap();
+        assert_eq!(after.index_root, before.index_root);
+        assert_eq!(after.free_space_root, before.free_space_root);
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::OpenExisting).unwrap();
+        assert_eq!(collection.len(), 3);
+        assert_eq!(collection.write(String::from("next")).unwrap(), 5);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+
+use crate::storage::{block::{Block, BlockKind}, block_stroage::{BlockSeek, Reader, ReaderError}, serialization::FromBytes, slotted::SlottedPage};
+
+// Every block of the file in position order, see Reader::scan. A block that can't be read is
+// reported and the scan goes on with the next one
+pub struct BlockScan<'a> {
+    reader: &'a Reader,
+    next: u64,
+    end: u64, // block count when the scan started, blocks added since aren't visited
+}
+
+impl<'a> BlockScan<'a> {
+    pub fn new(reader: &'a Reader, end: u64) -> Self {
+        Self { reader, next: 0, end }
+    }
+}
+
+impl Iterator for BlockScan<'_> {
+    type Item = Result<(u64, Block), ReaderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let position = self.next;
+        self.next += 1;
+        Some(self.reader.read_block(BlockSeek::Start(position)).map(|block| (position, block)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = (self.end - self.next) as usize;
+        (left, Some(left))
+    }
+}
+
+// Id and contents of every document the file holds, found by its blocks alone, so the index
+// isn't needed. Chains are read from their first block and pages yield each of their documents,
+// free blocks, continuations and the storage's own lists are skipped
+pub struct DocumentScan<'a> {
+    blocks: BlockScan<'a>,
+    page: VecDeque<(u64, Vec<u8>)>, // rest of the page being visited
+}
+
+impl<'a> DocumentScan<'a> {
+    pub fn new(blocks: BlockScan<'a>) -> Self {
+        Self { blocks, page: VecDeque::new() }
+    }
+}
+
+impl Iterator for DocumentScan<'_> {
+    type Item = Result<(u64, Vec<u8>), ReaderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(document) = self.page.pop_front() {
+                return Some(Ok(document));
+            }
+            let (position, block) = match self.blocks.next()? {
+                Ok(found) => found,
+                Err(err) => return Some(Err(err)),
+            };
+            match block.get_kind() {
+                BlockKind::Document => {
+                    let data = self.blocks.reader.read_full_item(BlockSeek::Start(position));
+                    return Some(data.map(|data| (block.get_id(), data)));
+                },
+                BlockKind::Slotted => match SlottedPage::from_bytes_vec(&block.data) {
+                    Ok(page) => self.page.extend(page.iter().map(|(id, data)| (id, data.to_vec()))),
+                    Err(err) => return Some(Err(err.into())),
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::storage::{block::{DEFAULT_BLOCK_SIZE, DEFAULT_DATA_SIZE}, block_stroage::{BlockStorage, OpenMode, StorageOption, Writer}, header::HEADER_SIZE, memory::MemoryBuffer};
+
+    #[test]
+    fn test_scan_documents() {
+        let storage = BlockStorage::open(StorageOption::Memory(MemoryBuffer::new()), OpenMode::CreateNew).unwrap();
+        let large = vec![1u8; DEFAULT_DATA_SIZE * 2];
+        storage.write_document(1, &large).unwrap();
+        storage.write_document(2, b"small").unwrap();
+        storage.write_document(3, b"tiny").unwrap();
+        let head = storage.write_document(4, &[4u8; DEFAULT_DATA_SIZE]).unwrap();
+        storage.release_document(4, head).unwrap();
+        let page = storage.write_document(5, b"gone").unwrap();
+        storage.release_document(5, page).unwrap();
+
+        let kinds: Vec<BlockKind> = storage.scan().unwrap().map(|found| found.unwrap().1.get_kind()).collect();
+        assert_eq!(kinds.len() as u64, storage.block_count().unwrap());
+        assert_eq!(kinds.iter().filter(|kind| **kind == BlockKind::Document).count(), 1);
+        assert_eq!(kinds.iter().filter(|kind| **kind == BlockKind::Slotted).count(), 1);
+        assert!(kinds.contains(&BlockKind::FreeSpace));
+
+        let mut documents: Vec<(u64, Vec<u8>)> = storage.documents().unwrap().map(Result::unwrap).collect();
+        documents.sort();
+        assert_eq!(documents, vec![(1, large), (2, b"small".to_vec()), (3, b"tiny".to_vec())]);
+    }
+
+    #[test]
+    fn test_scan_goes_past_corrupt_blocks() {
+        let buffer = MemoryBuffer::new();
+        let writer = Writer::new(StorageOption::Memory(buffer.clone())).unwrap();
+        writer.write_full_item(1, b"broken").unwrap();
+        writer.write_full_item(2, b"fine").unwrap();
+
+        let mut cursor = buffer.cursor();
+        let flipped_at = HEADER_SIZE as u64 + 100;
+        let mut byte = [0u8];
+        cursor.seek(SeekFrom::Start(flipped_at)).unwrap();
+        cursor.read_exact(&mut byte).unwrap();
+        cursor.seek(SeekFrom::Start(flipped_at)).unwrap();
+        cursor.write_all(&[byte[0] ^ 1]).unwrap();
+
+        let reader = Reader::new(StorageOption::Memory(buffer.clone())).unwrap();
+        let blocks: Vec<_> = reader.scan().unwrap().collect();
+        assert_eq!(blocks.len(), 2);
+        assert!(matches!(blocks[0], Err(ReaderError::Corrupted { position: 0, .. })));
+        assert!(matches!(&blocks[1], Ok((1, block)) if block.get_id() == 2));
+
+        let documents: Vec<_> = reader.documents().unwrap().collect();
+        assert_eq!(documents.len(), 2);
+        assert!(documents[0].is_err());
+        assert_eq!(documents[1].as_ref().unwrap(), &(2, b"fine".to_vec()));
+        assert_eq!(buffer.len().unwrap(), (HEADER_SIZE + 2 * DEFAULT_BLOCK_SIZE) as u64);
+    }
+}
@@ -6,10 +6,9 @@ pub enum SizeExtraction {
     FromStart
 }
 fn add_vectors_collect<T: Clone>(vec1: Vec<T>, vec2: Vec<T>) -> Vec<T> {
-    vec1.into_iter().chain(vec2.into_iter()).collect()
+    vec1.into_iter().chain(vec2).collect()
 }
 
-#[allow(dead_code)]
 pub trait ToBytes {
     fn to_bytes_vec(&self) -> Vec<u8>;
 }
@@ -49,7 +48,7 @@ impl<T: ToBytes> ToBytes for Vec<T> {
     fn to_bytes_vec(&self) -> Vec<u8> {
         let vec_len = self.len();
         let out_vec: Vec<u8> = vec_len.to_bytes_vec();
-        let vec_to_u8: Vec<u8> = self.iter().map(|item| item.to_bytes_vec()).flatten().collect();
+        let vec_to_u8: Vec<u8> = self.iter().flat_map(|item| item.to_bytes_vec()).collect();
         add_vectors_collect(out_vec, vec_to_u8)
     }
 }
@@ -71,6 +70,7 @@ pub enum FromBytesError {
     Utf8Error(std::string::FromUtf8Error),
     Io(std::io::Error),
     ReadLenError,
+    UnknownBlockKind(u8),
 }
 
 impl From<std::io::Error> for FromBytesError {
@@ -86,7 +86,6 @@ impl From<FromUtf8Error> for FromBytesError {
 }
 
 // read trait
-#[allow(dead_code)]
 pub trait FromBytes {
     fn from_bytes_vec(bytes: &[u8]) -> Result<Self, FromBytesError> where Self:Sized;
     fn get_size_strategy() -> SizeExtraction;
@@ -200,17 +199,16 @@ pub fn read_bytes<T: FromBytes>(reader: &mut dyn Read) -> Result<T, FromBytesErr
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
+    use crate::storage::memory::MemoryBuffer;
 
     #[test]
     fn test_io_u64() {
         let value: u64 = 15;
-        let mut buffer = File::create("data/foou64.dat").unwrap();
-        let res = write_bytes(&mut buffer, value);
+        let buffer = MemoryBuffer::new();
+        let res = write_bytes(&mut buffer.cursor(), value);
         assert_eq!(res.unwrap(), ());
 
-        let mut buffer= File::open("data/foou64.dat").unwrap();
-        let new_res: Result<u64, FromBytesError> = read_bytes(&mut buffer);
+        let new_res: Result<u64, FromBytesError> = read_bytes(&mut buffer.cursor());
         assert_eq!(new_res.unwrap(), value);
     }
 
@@ -218,12 +216,11 @@ mod tests {
     #[test]
     fn test_io_i64() {
         let value: i64 = -19;
-        let mut buffer = File::create("data/fooi64.dat").unwrap();
-        let res = write_bytes(&mut buffer, value);
+        let buffer = MemoryBuffer::new();
+        let res = write_bytes(&mut buffer.cursor(), value);
         assert_eq!(res.unwrap(), ());
 
-        let mut buffer= File::open("data/fooi64.dat").unwrap();
-        let new_res: Result<i64, FromBytesError> = read_bytes(&mut buffer);
+        let new_res: Result<i64, FromBytesError> = read_bytes(&mut buffer.cursor());
         assert_eq!(new_res.unwrap(), value);
     }
 
@@ -231,12 +228,11 @@ mod tests {
     #[test]
     fn test_io_u32() {
         let value: u32 = 30;
-        let mut buffer = File::create("data/foou32.dat").unwrap();
-        let res = write_bytes(&mut buffer, value);
+        let buffer = MemoryBuffer::new();
+        let res = write_bytes(&mut buffer.cursor(), value);
         assert_eq!(res.unwrap(), ());
 
-        let mut buffer= File::open("data/foou32.dat").unwrap();
-        let new_res: Result<u32, FromBytesError> = read_bytes(&mut buffer);
+        let new_res: Result<u32, FromBytesError> = read_bytes(&mut buffer.cursor());
         assert_eq!(new_res.unwrap(), value);
     }
 
@@ -244,12 +240,11 @@ mod tests {
     #[test]
     fn test_io_i32() {
         let value: i32 = -12;
-        let mut buffer = File::create("data/fooi32.dat").unwrap();
-        let res = write_bytes(&mut buffer, value);
+        let buffer = MemoryBuffer::new();
+        let res = write_bytes(&mut buffer.cursor(), value);
         assert_eq!(res.unwrap(), ());
 
-        let mut buffer= File::open("data/fooi32.dat").unwrap();
-        let new_res: Result<i32, FromBytesError> = read_bytes(&mut buffer);
+        let new_res: Result<i32, FromBytesError> = read_bytes(&mut buffer.cursor());
         assert_eq!(new_res.unwrap(), value);
     }
 
@@ -258,12 +253,11 @@ mod tests {
         let value = String::from("יאיר אשל דובר עברית");
         let value_clone = value.clone();
 
-        let mut buffer = File::create("data/foostring.dat").unwrap();
-        let res = write_bytes(&mut buffer, value);
+        let buffer = MemoryBuffer::new();
+        let res = write_bytes(&mut buffer.cursor(), value);
         assert_eq!(res.unwrap(), ());
 
-        let mut buffer= File::open("data/foostring.dat").unwrap();
-        let new_res: Result<String, FromBytesError> = read_bytes(&mut buffer);
+        let new_res: Result<String, FromBytesError> = read_bytes(&mut buffer.cursor());
         assert_eq!(new_res.unwrap(), value_clone);
 
     }
@@ -273,15 +267,15 @@ mod tests {
         let value:Vec<u64> = vec![10, 20, 30];
         // let value_clone = value.clone();
 
-        let mut buffer = File::create("data/foovec46.dat").unwrap();
-        let res = write_bytes(&mut buffer, value);
+        let buffer = MemoryBuffer::new();
+        let res = write_bytes(&mut buffer.cursor(), value);
         assert_eq!(res.unwrap(), ());
 
         let value:Vec<String> = vec!["I am the smart".to_string(), "I am stupid".to_string()];
         // let value_clone = value.clone();
 
-        let mut buffer = File::create("data/foovecstrings.dat").unwrap();
-        let res = write_bytes(&mut buffer, value);
+        let buffer = MemoryBuffer::new();
+        let res = write_bytes(&mut buffer.cursor(), value);
         assert_eq!(res.unwrap(), ());
 
         
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use crate::storage::serialization::{FromBytes, FromBytesError, SizeExtraction, ToBytes};
+
+const COUNT_SIZE: usize = 4;
+const SLOT_SIZE: usize = 16; // document id + offset + length
+
+// Data area of a block shared by several small documents. A slot directory grows from the start
+// of the area and the documents are packed from its end, whatever is in between is free
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlottedPage {
+    capacity: usize,
+    entries: BTreeMap<u64, Vec<u8>>,
+}
+
+impl SlottedPage {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: BTreeMap::new() }
+    }
+
+    // Room a document of `len` bytes takes, its slot included
+    pub fn entry_size(len: usize) -> usize {
+        SLOT_SIZE + len
+    }
+
+    // Largest document that fits an empty page of `capacity` bytes
+    pub fn max_document_len(capacity: usize) -> usize {
+        capacity.saturating_sub(COUNT_SIZE + SLOT_SIZE)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn free_space(&self) -> usize {
+        let used: usize = self.entries.values().map(|data| Self::entry_size(data.len())).sum();
+        self.capacity - COUNT_SIZE - used
+    }
+
+    pub fn get(&self, id: u64) -> Option<&[u8]> {
+        self.entries.get(&id).map(Vec::as_slice)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.entries.keys().copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[u8])> + '_ {
+        self.entries.iter().map(|(id, data)| (*id, data.as_slice()))
+    }
+
+    // Adds or replaces a document, false when the page has no room for it
+    pub fn insert(&mut self, id: u64, data: &[u8]) -> bool {
+        let freed = self.entries.get(&id).map_or(0, |old| Self::entry_size(old.len()));
+        if Self::entry_size(data.len()) > self.free_space() + freed {
+            return false;
+        }
+        self.entries.insert(id, data.to_vec());
+        true
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Vec<u8>> {
+        self.entries.remove(&id)
+    }
+}
+
+impl ToBytes for SlottedPage {
+    fn to_bytes_vec(&self) -> Vec<u8> {
+        let mut out_vec = vec![0u8; self.capacity];
+        out_vec[..COUNT_SIZE].copy_from_slice(&(self.entries.len() as u32).to_bytes_vec());
+
+        let mut data_start = self.capacity;
+        for (i, (id, data)) in self.entries.iter().enumerate() {
+            data_start -= data.len();
+            out_vec[data_start..data_start + data.len()].copy_from_slice(data);
+
+            let mut slot = id.to_bytes_vec();
+            slot.extend((data_start as u32).to_bytes_vec());
+            slot.extend((data.len() as u32).to_bytes_vec());
+            let slot_start = COUNT_SIZE + i * SLOT_SIZE;
+            out_vec[slot_start..slot_start + SLOT_SIZE].copy_from_slice(&slot);
+        }
+        out_vec
+    }
+}
+
+impl FromBytes for SlottedPage {
+    fn from_bytes_vec(bytes: &[u8]) -> Result<Self, FromBytesError> where Self:Sized {
+        if bytes.len() < COUNT_SIZE {
+            return Err(FromBytesError::ReadLenError);
+        }
+        let count = u32::from_bytes_vec(&bytes[..COUNT_SIZE])? as usize;
+        let directory_end = COUNT_SIZE + count * SLOT_SIZE;
+        if directory_end > bytes.len() {
+            return Err(FromBytesError::ReadLenError);
+        }
+
+        let mut page = Self::new(bytes.len());
+        for slot in bytes[COUNT_SIZE..directory_end].chunks(SLOT_SIZE) {
+            let id = u64::from_bytes_vec(&slot[..8])?;
+            let offset = u32::from_bytes_vec(&slot[8..12])? as usize;
+            let len = u32::from_bytes_vec(&slot[12..])? as usize;
+            if offset < directory_end || offset + len > bytes.len() {
+                return Err(FromBytesError::ReadLenError); // a slot pointing into the directory or past the page
+            }
+            page.entries.insert(id, bytes[offset..offset + len].to_vec());
+        }
+        Ok(page)
+    }
+
+    fn get_size_strategy() -> SizeExtraction {
+        SizeExtraction::FromStart
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_and_unpack() {
+        let mut page = SlottedPage::new(100);
+        assert_eq!(page.free_space(), 96);
+        assert!(page.insert(7, b"seven"));
+        assert!(page.insert(3, b""));
+        assert!(page.insert(12, b"twelve!"));
+        assert_eq!(page.free_space(), 96 - 3 * 16 - 12);
+        assert_eq!(page.len(), 3);
+
+        let bytes = page.to_bytes_vec();
+        assert_eq!(bytes.len(), 100);
+        assert_eq!(&bytes[95..], b"seven"); // packed from the end, in id order
+        assert_eq!(&bytes[88..95], b"twelve!");
+
+        let loaded = SlottedPage::from_bytes_vec(&bytes).unwrap();
+        assert_eq!(loaded, page);
+        assert_eq!(loaded.get(12), Some(&b"twelve!"[..]));
+        assert_eq!(loaded.get(3), Some(&b""[..]));
+        assert_eq!(loaded.ids().collect::<Vec<_>>(), vec![3, 7, 12]);
+    }
+
+    #[test]
+    fn test_full_page() {
+        let mut page = SlottedPage::new(64);
+        assert_eq!(SlottedPage::max_document_len(64), 44);
+        assert!(!page.insert(1, &[1u8; 45]));
+        assert!(page.insert(1, &[1u8; 20]));
+        assert!(!page.insert(2, &[2u8; 20]));
+
+        // replacing a document counts the room its old version frees
+        assert!(page.insert(1, &[1u8; 44]));
+        assert_eq!(page.free_space(), 0);
+        assert_eq!(page.remove(1), Some(vec![1u8; 44]));
+        assert!(page.is_empty());
+    }
+
+    #[test]
+    fn test_reject_bad_slots() {
+        let mut page = SlottedPage::new(64);
+        page.insert(1, b"abc");
+        let mut bytes = page.to_bytes_vec();
+        bytes[COUNT_SIZE + 8] = 2; // offset inside the directory
+        assert!(matches!(SlottedPage::from_bytes_vec(&bytes), Err(FromBytesError::ReadLenError)));
+
+        let mut bytes = page.to_bytes_vec();
+        bytes[0] = 200; // more slots than the page can hold
+        assert!(matches!(SlottedPage::from_bytes_vec(&bytes), Err(FromBytesError::ReadLenError)));
+    }
+}
@@ -0,0 +1,460 @@
+use std::{collections::{BTreeSet, HashMap, HashSet}, fmt, path::Path};
+
+use crate::storage::{block::BlockKind, block_stroage::{BlockSeek, BlockStorage, OpenMode, ReaderError, StorageConfig, StorageError, StorageOption}, free_space::FreeSpace, index::Index, serialization::{FromBytes, ToBytes}, slotted::SlottedPage};
+
+// Something wrong with a block file, every position is a block position
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    Corrupted { position: u64 },                                 // bad checksum, or bytes that aren't a block
+    BadRoot { root: &'static str, position: u64 },               // the index or free space list the header points at can't be read
+    WrongKind { position: u64, expected: BlockKind, found: BlockKind },
+    DanglingLink { position: u64, next: u64 },                   // points past the last block
+    Cycle { head: u64, position: u64 },                          // the chain starting at `head` comes back to `position`
+    SharedBlock { position: u64, first_head: u64, second_head: u64 },
+    MissingDocument { id: u64, position: u64 },                  // the index points at a block that doesn't hold the document
+    FreeBlockInUse { position: u64 },                            // on the free space list, but not a free block
+    UnlistedFreeBlock { position: u64 },                         // free, but never handed out again
+    Orphaned { position: u64 },                                  // in use, but no index entry or root reaches it
+    PendingLog,                                                  // a crash left commits in the log, the blocks may not have them yet
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Corrupted { position } => write!(f, "block {position} is corrupted"),
+            Self::BadRoot { root, position } => write!(f, "the {root} at block {position} can't be read"),
+            Self::WrongKind { position, expected, found } => write!(f, "block {position} is {found:?}, expected {expected:?}"),
+            Self::DanglingLink { position, next } => write!(f, "block {position} links to block {next}, past the end of the file"),
+            Self::Cycle { head, position } => write!(f, "the chain at block {head} loops back to block {position}"),
+            Self::SharedBlock { position, first_head, second_head } => write!(f, "block {position} belongs to the chains at blocks {first_head} and {second_head}"),
+            Self::MissingDocument { id, position } => write!(f, "document {id} is not at block {position}"),
+            Self::FreeBlockInUse { position } => write!(f, "block {position} is on the free space list but in use"),
+            Self::UnlistedFreeBlock { position } => write!(f, "block {position} is free but not on the free space list"),
+            Self::Orphaned { position } => write!(f, "block {position} is not reachable"),
+            Self::PendingLog => write!(f, "the log holds commits that may not have reached the blocks, repairing replays them"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    pub blocks: u64,
+    pub documents: usize, // readable ones
+    pub free_blocks: usize,
+    pub problems: Vec<Problem>,
+    pub repaired: bool,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockInfo {
+    kind: BlockKind,
+    next: Option<u64>,
+}
+
+// One pass over a block file, remembering which chain or page owns every block
+struct Check<'a> {
+    storage: &'a BlockStorage,
+    blocks: Vec<Option<BlockInfo>>, // None for blocks that can't be read
+    owners: HashMap<u64, u64>,      // block -> first block of the intact chain or page it belongs to
+    pages: HashMap<u64, Option<SlottedPage>>,
+    index: Index,
+    free_space: FreeSpace,
+    missing: Vec<u64>, // index entries without a readable document
+    bad_root: bool,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Check<'a> {
+    fn run(storage: &'a BlockStorage) -> Result<Self, ReaderError> {
+        let mut check = Self {
+            storage,
+            blocks: vec![],
+            owners: HashMap::new(),
+            pages: HashMap::new(),
+            index: Index::new(),
+            free_space: FreeSpace::new(),
+            missing: vec![],
+            bad_root: false,
+            problems: vec![],
+        };
+
+        for position in 0..storage.block_count()? {
+            let info = match storage.read_block(BlockSeek::Start(position)) {
+                Ok(block) => Some(BlockInfo { kind: block.get_kind(), next: block.get_next_block() }),
+                Err(ReaderError::Corrupted { .. } | ReaderError::FromBytesError(_)) => {
+                    check.problems.push(Problem::Corrupted { position });
+                    None
+                },
+                Err(err) => return Err(err),
+            };
+            check.blocks.push(info);
+        }
+
+        let header = storage.read_header()?;
+        if let Some(root) = header.free_space_root
+            && let Some((bytes, chain)) = check.read_root("free space list", root, BlockKind::FreeSpace) {
+            match FreeSpace::from_bytes_vec(&bytes) {
+                Ok(mut free_space) => {
+                    free_space.set_chain(chain);
+                    check.free_space = free_space;
+                },
+                Err(_) => check.root_problem("free space list", root),
+            }
+        }
+        if let Some(root) = header.index_root
+            && let Some((bytes, chain)) = check.read_root("index", root, BlockKind::Index) {
+            match Index::from_bytes_vec(&bytes) {
+                Ok(mut index) => {
+                    index.set_chain(chain);
+                    check.index = index;
+                },
+                Err(_) => check.root_problem("index", root),
+            }
+        }
+
+        let entries: Vec<(u64, u64)> = check.index.iter().collect();
+        for (id, head) in entries {
+            if !check.check_document(id, head) {
+                check.missing.push(id);
+            }
+        }
+        check.check_free_blocks();
+        Ok(check)
+    }
+
+    fn root_problem(&mut self, root: &'static str, position: u64) {
+        self.problems.push(Problem::BadRoot { root, position });
+        self.bad_root = true;
+    }
+
+    fn read_root(&mut self, root: &'static str, position: u64, kind: BlockKind) -> Option<(Vec<u8>, Vec<u64>)> {
+        let chain = self.walk_chain(position, kind);
+        let bytes = chain.as_ref().and_then(|_| self.storage.read_full_item(BlockSeek::Start(position)).ok());
+        match (bytes, chain) {
+            (Some(bytes), Some(chain)) => Some((bytes, chain)),
+            _ => {
+                self.root_problem(root, position);
+                None
+            },
+        }
+    }
+
+    // Follows the chain at `head`, claiming its blocks when it's intact
+    fn walk_chain(&mut self, head: u64, kind: BlockKind) -> Option<Vec<u64>> {
+        let mut chain = vec![];
+        let mut seen = HashSet::new();
+        let mut position = head;
+        loop {
+            if !seen.insert(position) {
+                self.problems.push(Problem::Cycle { head, position });
+                return None;
+            }
+            let info = (*self.blocks.get(position as usize)?)?; // corrupted blocks were reported already
+            let expected = if chain.is_empty() { kind } else { BlockKind::Overflow };
+            if info.kind != expected {
+                self.problems.push(Problem::WrongKind { position, expected, found: info.kind });
+                return None;
+            }
+            if let Some(first_head) = self.owners.get(&position) {
+                self.problems.push(Problem::SharedBlock { position, first_head: *first_head, second_head: head });
+                return None;
+            }
+            chain.push(position);
+
+            match info.next {
+                Some(next) if next as usize >= self.blocks.len() => {
+                    self.problems.push(Problem::DanglingLink { position, next });
+                    return None;
+                },
+                Some(next) => position = next,
+                None => break,
+            }
+        }
+        for position in &chain {
+            self.owners.insert(*position, head);
+        }
+        Some(chain)
+    }
+
+    // Whether the document the index points at can be read
+    fn check_document(&mut self, id: u64, head: u64) -> bool {
+        let kind = self.blocks.get(head as usize).copied().flatten().map(|info| info.kind);
+        match kind {
+            Some(BlockKind::Document) => self.walk_chain(head, BlockKind::Document).is_some(),
+            Some(BlockKind::Slotted) => {
+                if !self.pages.contains_key(&head) {
+                    let page = self.load_page(head);
+                    self.pages.insert(head, page);
+                }
+                let found = self.pages[&head].as_ref().map(|page| page.get(id).is_some());
+                if found == Some(false) {
+                    self.problems.push(Problem::MissingDocument { id, position: head });
+                }
+                found == Some(true)
+            },
+            None if head as usize >= self.blocks.len() => {
+                self.problems.push(Problem::MissingDocument { id, position: head });
+                false
+            },
+            None => false, // reported as corrupted
+            Some(_) => {
+                self.problems.push(Problem::MissingDocument { id, position: head });
+                false
+            },
+        }
+    }
+
+    fn load_page(&mut self, position: u64) -> Option<SlottedPage> {
+        if let Some(first_head) = self.owners.get(&position) {
+            self.problems.push(Problem::SharedBlock { position, first_head: *first_head, second_head: position });
+            return None;
+        }
+        let block = self.storage.read_block(BlockSeek::Start(position)).ok()?;
+        match SlottedPage::from_bytes_vec(&block.data) {
+            Ok(page) => {
+                self.owners.insert(position, position);
+                Some(page)
+            },
+            Err(_) => {
+                self.problems.push(Problem::Corrupted { position });
+                None
+            },
+        }
+    }
+
+    fn check_free_blocks(&mut self) {
+        let listed: BTreeSet<u64> = self.free_space.positions().collect();
+        for position in &listed {
+            match self.blocks.get(*position as usize) {
+                Some(Some(info)) if info.kind == BlockKind::Free => {},
+                None => {}, // past the end, the writer appends there anyway
+                _ => self.problems.push(Problem::FreeBlockInUse { position: *position }),
+            }
+        }
+        for (position, info) in self.blocks.iter().enumerate() {
+            let position = position as u64;
+            match info {
+                _ if self.owners.contains_key(&position) => {},
+                Some(info) if info.kind == BlockKind::Free && !listed.contains(&position) => {
+                    self.problems.push(Problem::UnlistedFreeBlock { position });
+                },
+                Some(info) if info.kind == BlockKind::Free => {},
+                Some(_) => self.problems.push(Problem::Orphaned { position }),
+                None => {},
+            }
+        }
+    }
+
+    fn report(&self) -> VerifyReport {
+        VerifyReport {
+            blocks: self.blocks.len() as u64,
+            documents: self.index.len() - self.missing.len(),
+            free_blocks: self.free_space.len(),
+            problems: self.problems.clone(),
+            repaired: false,
+        }
+    }
+
+    // Frees every block nothing reaches, then drops index entries whose documents are gone. The index
+    // goes second, blocks it no longer needs are freed onto the new list
+    fn repair(&self) -> Result<(), StorageError> {
+        let mut free_space = FreeSpace::new();
+        free_space.set_chain(self.free_space.get_chain().to_vec());
+        let mut release = vec![];
+        for (position, info) in self.blocks.iter().enumerate() {
+            let position = position as u64;
+            match info {
+                _ if self.owners.contains_key(&position) => {},
+                Some(info) if info.kind == BlockKind::Free => free_space.release(&[position]),
+                _ => release.push(position),
+            }
+        }
+        self.storage.rebuild_free_space(free_space, &release)?;
+
+        if !self.missing.is_empty() {
+            let mut index = self.index.clone();
+            for id in &self.missing {
+                index.remove(*id);
+            }
+            self.storage.rewrite_item(BlockKind::Index, 0, &index.to_bytes_vec(), index.get_chain())?;
+        }
+        Ok(())
+    }
+}
+
+// Checks every block of the file without changing it
+pub fn verify(path: &Path) -> Result<VerifyReport, StorageError> {
+    verify_storage(StorageOption::File(path.to_path_buf()))
+}
+
+pub fn verify_storage(stored_in: StorageOption) -> Result<VerifyReport, StorageError> {
+    // only a writer can replay the log, the blocks are checked as they are
+    let pending_log = BlockStorage::has_pending_log(&stored_in)?;
+    let config = StorageConfig { allow_pending_log: true, ..StorageConfig::default() };
+    let storage = BlockStorage::open_with_config(stored_in, OpenMode::ReadOnly, config)?;
+    let mut report = Check::run(&storage)?.report();
+    if pending_log {
+        report.problems.insert(0, Problem::PendingLog);
+    }
+    Ok(report)
+}
+
+// Verifies the file and fixes what can be fixed without guessing, in one transaction. Documents that
+// can't be read are dropped from the index, and unreachable blocks are freed. A file whose index or
+// free space list is broken is left alone
+pub fn repair(path: &Path) -> Result<VerifyReport, StorageError> {
+    repair_storage(StorageOption::File(path.to_path_buf()))
+}
+
+pub fn repair_storage(stored_in: StorageOption) -> Result<VerifyReport, StorageError> {
+    let storage = BlockStorage::open(stored_in, OpenMode::OpenExisting)?;
+    let check = Check::run(&storage)?;
+    let mut report = check.report();
+    if report.is_ok() || check.bad_root {
+        return Ok(report);
+    }
+
+    storage.begin()?;
+    if let Err(err) = check.repair() {
+        return match storage.rollback() {
+            Ok(()) => Err(err),
+            Err(rollback) => Err(StorageError::RollbackFailed { cause: Box::new(err), rollback }),
+        };
+    }
+    storage.commit()?;
+    report.repaired = true;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collection::Collection, storage::{block::{Block, DEFAULT_BLOCK_SIZE, DEFAULT_DATA_SIZE}, header::HEADER_SIZE, memory::MemoryBuffer, wal::{Wal, WalEntry}}};
+
+    // A document chain of three blocks and a page of small documents
+    fn setup() -> (MemoryBuffer, Index) {
+        let buffer = MemoryBuffer::new();
+        let mut collection = Collection::open_storage(StorageOption::Memory(buffer.clone()), OpenMode::CreateNew).unwrap();
+        collection.write("x".repeat(DEFAULT_DATA_SIZE * 2 + 5)).unwrap();
+        collection.write(String::from("small")).unwrap();
+        collection.write(String::from("smaller")).unwrap();
+        drop(collection);
+
+        let storage = BlockStorage::open(StorageOption::Memory(buffer.clone()), OpenMode::ReadOnly).unwrap();
+        let root = storage.read_header().unwrap().index_root.unwrap();
+        let index = Index::from_bytes_vec(&storage.read_full_item(BlockSeek::Start(root)).unwrap()).unwrap();
+        (buffer, index)
+    }
+
+    fn edit_block(buffer: &MemoryBuffer, position: u64, edit: impl FnOnce(&mut Block)) {
+        let mut bytes = buffer.to_vec().unwrap();
+        let start = HEADER_SIZE + position as usize * DEFAULT_BLOCK_SIZE;
+        let mut block = Block::from_bytes_vec(&bytes[start..start + DEFAULT_BLOCK_SIZE]).unwrap();
+        edit(&mut block);
+        bytes[start..start + DEFAULT_BLOCK_SIZE].copy_from_slice(&block.to_bytes_vec());
+        buffer.set_contents(bytes).unwrap();
+    }
+
+    fn problems(buffer: &MemoryBuffer) -> Vec<Problem> {
+        verify_storage(StorageOption::Memory(buffer.clone())).unwrap().problems
+    }
+
+    #[test]
+    fn test_clean_file() {
+        let (buffer, _) = setup();
+        let report = verify_storage(StorageOption::Memory(buffer.clone())).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.documents, 3);
+
+        let report = repair_storage(StorageOption::Memory(buffer)).unwrap();
+        assert!(!report.repaired);
+    }
+
+    #[test]
+    fn test_pending_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.db");
+        let mut collection = Collection::open(path.clone()).unwrap();
+        collection.write(String::from("logged")).unwrap();
+        drop(collection);
+
+        // a crash right after the commit reached the log
+        let bytes = std::fs::read(&path).unwrap();
+        Wal::open(&Wal::path_for(&path)).unwrap().log(&[WalEntry { offset: 0, bytes }]).unwrap();
+        let report = verify(&path).unwrap();
+        assert_eq!(report.problems, vec![Problem::PendingLog]);
+        assert_eq!(report.documents, 1);
+
+        let report = repair(&path).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert!(verify(&path).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_broken_chains() {
+        let (buffer, index) = setup();
+        let head = index.get(1).unwrap();
+        let second = BlockStorage::open(StorageOption::Memory(buffer.clone()), OpenMode::ReadOnly).unwrap()
+            .chain_positions(head).unwrap()[1];
+
+        edit_block(&buffer, second, |block| block.set_next_block(second));
+        assert!(problems(&buffer).contains(&Problem::Cycle { head, position: second }));
+
+        edit_block(&buffer, second, |block| block.set_next_block(10_000));
+        let found = problems(&buffer);
+        assert!(found.contains(&Problem::DanglingLink { position: second, next: 10_000 }));
+        assert!(found.contains(&Problem::Orphaned { position: head })); // a broken chain owns nothing
+
+        let page = index.get(2).unwrap();
+        edit_block(&buffer, second, |block| block.set_next_block(page));
+        assert!(problems(&buffer).contains(&Problem::WrongKind { position: page, expected: BlockKind::Overflow, found: BlockKind::Slotted }));
+    }
+
+    #[test]
+    fn test_bad_blocks() {
+        let (buffer, index) = setup();
+        let page = index.get(2).unwrap();
+        edit_block(&buffer, page, |block| *block = Block::new()); // deleted under the index
+        let found = problems(&buffer);
+        assert!(found.contains(&Problem::MissingDocument { id: 2, position: page }));
+        assert!(found.contains(&Problem::MissingDocument { id: 3, position: page }));
+        assert!(found.contains(&Problem::UnlistedFreeBlock { position: page }));
+
+        let head = index.get(1).unwrap();
+        let mut bytes = buffer.to_vec().unwrap();
+        bytes[HEADER_SIZE + head as usize * DEFAULT_BLOCK_SIZE + 20] ^= 1;
+        buffer.set_contents(bytes).unwrap();
+        assert!(problems(&buffer).contains(&Problem::Corrupted { position: head }));
+    }
+
+    #[test]
+    fn test_repair() {
+        let (buffer, index) = setup();
+        let head = index.get(1).unwrap();
+        edit_block(&buffer, head, |block| block.set_next_block(10_000));
+        let stray = BlockStorage::open(StorageOption::Memory(buffer.clone()), OpenMode::ReadOnly).unwrap().block_count().unwrap();
+        let mut bytes = buffer.to_vec().unwrap();
+        bytes.extend(Block::with_kind(BlockKind::Document, 9).to_bytes_vec());
+        buffer.set_contents(bytes).unwrap();
+
+        let found = problems(&buffer);
+        assert!(found.contains(&Problem::Orphaned { position: stray }));
+        let report = repair_storage(StorageOption::Memory(buffer.clone())).unwrap();
+        assert!(report.repaired);
+        assert_eq!(report.documents, 2);
+
+        // the unreadable document is gone, the rest is intact and the lost blocks can be reused
+        let report = verify_storage(StorageOption::Memory(buffer.clone())).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.free_blocks, 4);
+        let collection = Collection::open_storage(StorageOption::Memory(buffer), OpenMode::OpenExisting).unwrap();
+        assert_eq!(collection.len(), 2);
+        assert_eq!(collection.read(3).unwrap(), Some(&String::from("smaller")));
+    }
+}
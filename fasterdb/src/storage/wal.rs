@@ -0,0 +1,612 @@
+use std::{collections::VecDeque, fs::{File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc}};
+
+use crate::storage::{block_stroage::{SyncPolicy, WriteSeek}, checksum::crc32c, serialization::{FromBytes, ToBytes}};
+
+const LOG_HEADER_SIZE: usize = 8;     // sequence number of the first record that counts
+const RECORD_HEADER_SIZE: usize = 20; // body length + crc32c of sequence number and body + sequence number
+const ENTRY_HEADER_SIZE: usize = 16;  // file offset + byte count
+
+// Once the log holds this much, the block file is synced and the log starts over
+const CHECKPOINT_BYTES: usize = 1 << 20;
+
+pub trait LogFile: Read + WriteSeek {}
+impl<T: Read + WriteSeek> LogFile for T {}
+
+// A single write into the block file, logged before it is applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalEntry {
+    pub offset: u64,
+    pub bytes: Vec<u8>,
+}
+
+// Write-ahead log next to the block file, holds committed transactions until they reached the blocks on disk.
+// Records are appended with consecutive sequence numbers, and clearing the log only moves the first
+// sequence number that counts past them, so records left over from before can never be replayed
+pub struct Wal {
+    log: Box<dyn LogFile>,
+    sync_writes: bool, // off, the log is only as durable as the OS makes it
+    tail: Option<(u64, u64)>, // where the next record goes and its sequence number, found on first use
+    log_sync: LogSync,
+}
+
+// Syncs the log without going through the journal, so committers can wait for the disk without holding it
+#[derive(Clone)]
+pub struct LogSync {
+    file: Option<Arc<File>>, // None for a log that isn't a file, there's no disk to wait for
+    syncs: Arc<AtomicU64>,
+}
+
+impl LogSync {
+    pub fn sync(&self) -> std::io::Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_data()?;
+        }
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+impl WalEntry {
+    fn apply(&self, target: &mut dyn WriteSeek) -> std::io::Result<()> {
+        target.seek(SeekFrom::Start(self.offset))?;
+        target.write_all(&self.bytes)
+    }
+}
+
+impl Wal {
+    pub fn new(log: impl LogFile + 'static) -> Self {
+        Self::with_log_sync(log, None)
+    }
+
+    fn with_log_sync(log: impl LogFile + 'static, file: Option<File>) -> Self {
+        let log_sync = LogSync { file: file.map(Arc::new), syncs: Arc::new(AtomicU64::new(0)) };
+        Self { log: Box::new(log), sync_writes: true, tail: None, log_sync }
+    }
+
+    pub fn set_sync(&mut self, sync_writes: bool) {
+        self.sync_writes = sync_writes;
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        match self.sync_writes {
+            true => {
+                self.log.sync()?;
+                self.log_sync.syncs.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            false => self.log.flush(),
+        }
+    }
+
+    pub fn log_sync(&self) -> LogSync {
+        self.log_sync.clone()
+    }
+
+    // Times the log was synced, by the journal or through a LogSync
+    pub fn sync_count(&self) -> u64 {
+        self.log_sync.syncs.load(Ordering::Relaxed)
+    }
+
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file: File = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let sync_handle = file.try_clone()?;
+        Ok(Self::with_log_sync(file, Some(sync_handle)))
+    }
+
+    // The log of "data.db" lives in "data.db.wal"
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".wal");
+        PathBuf::from(name)
+    }
+
+    fn get_tail(&mut self) -> std::io::Result<(u64, u64)> {
+        match self.tail {
+            Some(tail) => Ok(tail),
+            None => {
+                self.committed()?;
+                Ok(self.tail.expect("committed finds the tail"))
+            },
+        }
+    }
+
+    // Appends the transaction as one checksummed record, once this returns it survives a crash
+    pub fn log(&mut self, entries: &[WalEntry]) -> std::io::Result<()> {
+        self.append(entries)?;
+        self.sync()
+    }
+
+    // Appends the record without waiting for the disk, and returns its sequence number
+    pub fn append(&mut self, entries: &[WalEntry]) -> std::io::Result<u64> {
+        let (end, sequence) = self.get_tail()?;
+        let mut body = vec![];
+        for entry in entries {
+            body.extend(entry.offset.to_bytes_vec());
+            body.extend((entry.bytes.len() as u64).to_bytes_vec());
+            body.extend_from_slice(&entry.bytes);
+        }
+
+        let mut checked = sequence.to_bytes_vec();
+        checked.extend_from_slice(&body);
+        let mut record = (body.len() as u64).to_bytes_vec();
+        record.extend(crc32c(&checked).to_bytes_vec());
+        record.extend(checked);
+
+        self.log.seek(SeekFrom::Start(end))?;
+        self.log.write_all(&record)?;
+        self.tail = Some((end + record.len() as u64, sequence + 1));
+        Ok(sequence)
+    }
+
+    // Bytes of the records that count, what a checkpoint would free
+    pub fn logged_bytes(&mut self) -> std::io::Result<u64> {
+        Ok(self.get_tail()?.0 - LOG_HEADER_SIZE as u64)
+    }
+
+    // Every logged write in order, the records end at the first one a crash tore or that's left over from before
+    pub fn committed(&mut self) -> std::io::Result<Vec<WalEntry>> {
+        let mut bytes = vec![];
+        self.log.seek(SeekFrom::Start(0))?;
+        self.log.read_to_end(&mut bytes)?;
+        let first_sequence = match bytes.get(..LOG_HEADER_SIZE) {
+            Some(header) => u64::from_bytes_vec(header).map_err(|_| invalid_data("Bad log header"))?,
+            None => 0,
+        };
+
+        let mut entries = vec![];
+        let mut position = LOG_HEADER_SIZE;
+        let mut sequence = first_sequence;
+        while let Some(header) = bytes.get(position..position + RECORD_HEADER_SIZE) {
+            let body_len = u64::from_bytes_vec(&header[..8]).map_err(|_| invalid_data("Bad log record length"))? as usize;
+            let crc = u32::from_bytes_vec(&header[8..12]).map_err(|_| invalid_data("Bad log record checksum"))?;
+            let record_sequence = u64::from_bytes_vec(&header[12..]).map_err(|_| invalid_data("Bad log record sequence"))?;
+            let checked_start = position + RECORD_HEADER_SIZE - 8;
+            let Some(checked) = bytes.get(checked_start..(checked_start + 8).saturating_add(body_len)) else {
+                break; // the crash hit before the whole record was written
+            };
+            if record_sequence != sequence || body_len == 0 || crc32c(checked) != crc {
+                break;
+            }
+
+            let mut rest = &checked[8..];
+            while !rest.is_empty() {
+                if rest.len() < ENTRY_HEADER_SIZE {
+                    return Err(invalid_data("Truncated log entry"));
+                }
+                let offset = u64::from_bytes_vec(&rest[..8]).map_err(|_| invalid_data("Bad log entry offset"))?;
+                let len = u64::from_bytes_vec(&rest[8..ENTRY_HEADER_SIZE]).map_err(|_| invalid_data("Bad log entry length"))? as usize;
+                let Some(bytes) = rest.get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE.saturating_add(len)) else {
+                    return Err(invalid_data("Truncated log entry"));
+                };
+                entries.push(WalEntry { offset, bytes: bytes.to_vec() });
+                rest = &rest[ENTRY_HEADER_SIZE + len..];
+            }
+            position += RECORD_HEADER_SIZE + body_len;
+            sequence += 1;
+        }
+        self.tail = Some((position as u64, sequence));
+        Ok(entries)
+    }
+
+    // Marks the logged transactions as applied, later records start after their sequence numbers
+    pub fn clear(&mut self) -> std::io::Result<()> {
+        let (_, sequence) = self.get_tail()?;
+        self.log.seek(SeekFrom::Start(0))?;
+        self.log.write_all(&sequence.to_bytes_vec())?;
+        self.sync()?;
+        self.tail = Some((LOG_HEADER_SIZE as u64, sequence));
+        Ok(())
+    }
+
+    // Reapplies a transaction that was logged but maybe not fully applied before a crash,
+    // applying it twice is harmless, so it's always replayed in full
+    pub fn recover(&mut self, target: &mut dyn WriteSeek) -> std::io::Result<usize> {
+        let entries = self.committed()?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        for entry in &entries {
+            entry.apply(target)?;
+        }
+        target.sync()?;
+        self.clear()?;
+        Ok(entries.len())
+    }
+}
+
+// Block file writer that stages writes while a transaction is open, and commits them through the log
+pub struct Journal {
+    inner: Box<dyn WriteSeek>,
+    wal: Option<Wal>, // None for storage that can't outlive a crash anyway, like memory
+    pending: Option<Vec<WalEntry>>,
+    // Commits in the log but not yet in the file, they're applied in order once the log reaches the disk
+    unapplied: VecDeque<(u64, Vec<WalEntry>)>,
+    applied_before: u64, // every commit logged with a lower sequence number is in the file
+    position: u64, // where staged writes go, the inner cursor is left alone until commit
+    sync_policy: SyncPolicy,
+    // Committed writes reached the file but maybe not the disk yet, the log keeps them until the next
+    // checkpoint, so commits share one sync of the file instead of paying for it each
+    unsynced: bool,
+    writes: u64, // writes that reached the file, so syncs can tell which ones they covered
+    syncs: u64,
+}
+
+impl Journal {
+    pub fn new(inner: Box<dyn WriteSeek>, wal: Option<Wal>) -> Self {
+        Self {
+            inner, wal, pending: None, unapplied: VecDeque::new(), applied_before: 0, position: 0,
+            sync_policy: SyncPolicy::OnCommit, unsynced: false, writes: 0, syncs: 0,
+        }
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.sync_policy = sync_policy;
+        if let Some(wal) = self.wal.as_mut() {
+            wal.set_sync(sync_policy == SyncPolicy::OnCommit);
+        }
+    }
+
+    pub fn write_count(&self) -> u64 {
+        self.writes
+    }
+
+    pub fn sync_count(&self) -> u64 {
+        self.syncs
+    }
+
+    pub fn log_sync_count(&self) -> u64 {
+        self.wal.as_ref().map_or(0, Wal::sync_count)
+    }
+
+    pub fn log_sync(&self) -> Option<LogSync> {
+        self.wal.as_ref().map(Wal::log_sync)
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // What the open transaction wrote, so caches over the file can follow once it commits
+    pub fn staged(&self) -> &[WalEntry] {
+        self.pending.as_deref().unwrap_or(&[])
+    }
+
+    // Newest sequence number waiting to be applied
+    pub fn logged_sequence(&self) -> Option<u64> {
+        self.unapplied.back().map(|(sequence, _)| *sequence)
+    }
+
+    pub fn applied_before(&self) -> u64 {
+        self.applied_before
+    }
+
+    // The bytes the newest unapplied commit wrote at `offset`, what a read there should see
+    pub fn unapplied_write(&self, offset: u64) -> Option<&[u8]> {
+        self.unapplied.iter().rev()
+            .flat_map(|(_, entries)| entries.iter().rev())
+            .find(|entry| entry.offset == offset)
+            .map(|entry| entry.bytes.as_slice())
+    }
+
+    pub fn begin(&mut self) -> std::io::Result<()> {
+        self.position = self.inner.stream_position()?;
+        self.pending = Some(vec![]);
+        Ok(())
+    }
+
+    // Logs the staged writes, then applies them to the block file, a crash in between is replayed on open.
+    // Syncing on commit, the transaction is durable once its log record is, the file is synced at the next checkpoint
+    pub fn commit(&mut self) -> std::io::Result<()> {
+        self.commit_logged()?;
+        self.settle()
+    }
+
+    // Like commit, but syncing on commit the record is only appended. The blocks may only reach the file
+    // after the log reaches the disk, so it's left to apply_logged, which a caller can run after syncing
+    // through a LogSync, without the journal locked. Returns the sequence number to wait for
+    pub fn commit_logged(&mut self) -> std::io::Result<Option<u64>> {
+        let Some(entries) = self.pending.take() else {
+            return Ok(None);
+        };
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        let Some(wal) = self.wal.as_mut() else {
+            self.apply(&entries)?;
+            return Ok(None);
+        };
+        if self.sync_policy != SyncPolicy::OnCommit {
+            // nothing waits for the disk, the log only covers a crash of the process
+            wal.log(&entries)?;
+            self.apply(&entries)?;
+            self.wal.as_mut().map_or(Ok(()), Wal::clear)?;
+            return Ok(None);
+        }
+
+        let sequence = wal.append(&entries)?;
+        self.unapplied.push_back((sequence, entries));
+        Ok(Some(sequence))
+    }
+
+    // Applies the commits logged up to `sequence`, the log holding them must be synced by now
+    pub fn apply_logged(&mut self, sequence: u64) -> std::io::Result<()> {
+        while let Some((first, _)) = self.unapplied.front() && *first <= sequence {
+            let (first, entries) = self.unapplied.pop_front().expect("the queue has a first commit");
+            self.unsynced = true;
+            self.apply(&entries)?;
+            self.applied_before = first + 1;
+        }
+        // clearing the log drops every record, so a checkpoint waits until none is left unapplied
+        if self.unapplied.is_empty() && let Some(wal) = self.wal.as_mut() && wal.logged_bytes()? >= CHECKPOINT_BYTES as u64 {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    // Syncs the log and applies every commit still waiting for it, before a write that must come after them
+    fn settle(&mut self) -> std::io::Result<()> {
+        let Some(sequence) = self.logged_sequence() else {
+            return Ok(());
+        };
+        if let Some(wal) = self.wal.as_mut() {
+            wal.sync()?;
+        }
+        self.apply_logged(sequence)
+    }
+
+    fn apply(&mut self, entries: &[WalEntry]) -> std::io::Result<()> {
+        for entry in entries {
+            entry.apply(self.inner.as_mut())?;
+            self.writes += 1;
+        }
+        Ok(())
+    }
+
+    // Syncs the block file, after which the log no longer needs the transactions it holds
+    fn checkpoint(&mut self) -> std::io::Result<()> {
+        self.settle()?;
+        self.inner.sync()?;
+        self.syncs += 1;
+        if let Some(wal) = self.wal.as_mut() && self.unsynced {
+            wal.clear()?;
+        }
+        self.unsynced = false;
+        Ok(())
+    }
+
+    pub fn rollback(&mut self) {
+        self.pending = None;
+    }
+
+    fn staged_end(&self) -> u64 {
+        self.pending.iter().flatten()
+            .chain(self.unapplied.iter().flat_map(|(_, entries)| entries))
+            .map(|entry| entry.offset + entry.bytes.len() as u64)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Write for Journal {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(pending) = self.pending.as_mut() else {
+            // the log would replay older commits over this write, so they leave it before it's made
+            if self.unsynced || self.logged_sequence().is_some() {
+                self.checkpoint()?;
+            }
+            self.writes += 1;
+            return self.inner.write(buf);
+        };
+        pending.push(WalEntry { offset: self.position, bytes: buf.to_vec() });
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.settle()?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for Journal {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        if self.pending.is_none() {
+            self.settle()?;
+            return self.inner.seek(pos);
+        }
+
+        // the end of the file includes blocks appended by the open transaction
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => {
+                let file_end = self.inner.seek(SeekFrom::End(0))?;
+                file_end.max(self.staged_end()).checked_add_signed(offset)
+            },
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the file")),
+        }
+    }
+}
+
+impl WriteSeek for Journal {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.checkpoint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryBuffer;
+
+    fn entries() -> Vec<WalEntry> {
+        vec![
+            WalEntry { offset: 0, bytes: b"head".to_vec() },
+            WalEntry { offset: 10, bytes: b"tail".to_vec() },
+        ]
+    }
+
+    #[test]
+    fn test_log_and_recover() {
+        let log = MemoryBuffer::new();
+        let mut wal = Wal::new(log.cursor());
+        assert!(wal.committed().unwrap().is_empty());
+
+        wal.log(&entries()).unwrap();
+        assert_eq!(Wal::new(log.cursor()).committed().unwrap(), entries());
+
+        let target = MemoryBuffer::new();
+        let mut cursor = target.cursor();
+        assert_eq!(wal.recover(&mut cursor).unwrap(), 2);
+        assert_eq!(target.to_vec().unwrap(), b"head\0\0\0\0\0\0tail");
+
+        // once applied, the log is empty and nothing is replayed again
+        assert!(wal.committed().unwrap().is_empty());
+        assert_eq!(wal.recover(&mut cursor).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_torn_record_is_ignored() {
+        let log = MemoryBuffer::new();
+        Wal::new(log.cursor()).log(&entries()).unwrap();
+        let record = log.to_vec().unwrap();
+
+        // cut short by a crash
+        let torn = MemoryBuffer::new();
+        torn.cursor().write_all(&record[..record.len() - 1]).unwrap();
+        assert!(Wal::new(torn.cursor()).committed().unwrap().is_empty());
+
+        // a byte of the body never made it to disk
+        let mut flipped = record.clone();
+        flipped[LOG_HEADER_SIZE + RECORD_HEADER_SIZE + 3] ^= 1;
+        let torn = MemoryBuffer::new();
+        torn.cursor().write_all(&flipped).unwrap();
+        assert!(Wal::new(torn.cursor()).committed().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_journal_stages_until_commit() {
+        let target = MemoryBuffer::new();
+        target.cursor().write_all(b"0123456789").unwrap();
+        let log = MemoryBuffer::new();
+        let mut journal = Journal::new(Box::new(target.cursor()), Some(Wal::new(log.cursor())));
+
+        journal.begin().unwrap();
+        journal.seek(SeekFrom::Start(2)).unwrap();
+        journal.write_all(b"ab").unwrap();
+        assert_eq!(journal.seek(SeekFrom::End(0)).unwrap(), 10);
+        journal.write_all(b"cd").unwrap();
+        assert_eq!(journal.seek(SeekFrom::End(0)).unwrap(), 12);
+        assert_eq!(target.to_vec().unwrap(), b"0123456789");
+
+        journal.commit().unwrap();
+        assert_eq!(target.to_vec().unwrap(), b"01ab456789cd");
+        assert_eq!(Wal::new(log.cursor()).committed().unwrap().len(), 2); // kept until the file is synced
+        journal.sync().unwrap();
+        assert!(Wal::new(log.cursor()).committed().unwrap().is_empty());
+
+        journal.begin().unwrap();
+        journal.write_all(b"zz").unwrap();
+        journal.rollback();
+        journal.commit().unwrap();
+        assert_eq!(target.to_vec().unwrap(), b"01ab456789cd");
+    }
+
+    #[test]
+    fn test_commits_share_a_log_sync() {
+        let target = MemoryBuffer::new();
+        let log = MemoryBuffer::new();
+        let mut journal = Journal::new(Box::new(target.cursor()), Some(Wal::new(log.cursor())));
+
+        let mut sequences = vec![];
+        for (offset, bytes) in [(0, b"ab"), (2, b"cd")] {
+            journal.begin().unwrap();
+            journal.seek(SeekFrom::Start(offset)).unwrap();
+            journal.write_all(bytes).unwrap();
+            sequences.push(journal.commit_logged().unwrap().unwrap());
+        }
+        // both are logged, neither is in the file before the log is synced
+        assert_eq!(Wal::new(log.cursor()).committed().unwrap().len(), 2);
+        assert!(target.to_vec().unwrap().is_empty());
+        assert_eq!(journal.unapplied_write(2), Some(&b"cd"[..]));
+        assert_eq!(journal.logged_sequence(), Some(sequences[1]));
+
+        journal.log_sync().unwrap().sync().unwrap();
+        journal.apply_logged(sequences[1]).unwrap();
+        assert_eq!(target.to_vec().unwrap(), b"abcd");
+        assert_eq!(journal.applied_before(), sequences[1] + 1);
+        assert_eq!(journal.log_sync_count(), 1);
+
+        // a write outside a transaction applies what's waiting and checkpoints first, so it lands after it
+        journal.begin().unwrap();
+        journal.seek(SeekFrom::Start(0)).unwrap();
+        journal.write_all(b"xy").unwrap();
+        journal.commit_logged().unwrap();
+        journal.seek(SeekFrom::Start(1)).unwrap();
+        journal.write_all(b"z").unwrap();
+        assert_eq!(target.to_vec().unwrap(), b"xzcd");
+        assert_eq!(journal.log_sync_count(), 3); // the record, then the cleared log
+        assert!(Wal::new(log.cursor()).committed().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_log_holds_commits_until_checkpoint() {
+        let target = MemoryBuffer::new();
+        let log = MemoryBuffer::new();
+        let mut journal = Journal::new(Box::new(target.cursor()), Some(Wal::new(log.cursor())));
+
+        for bytes in [b"ab", b"cd"] {
+            journal.begin().unwrap();
+            journal.write_all(bytes).unwrap();
+            journal.commit().unwrap();
+        }
+        // replaying the log redoes both commits, in order
+        let logged = Wal::new(log.cursor()).committed().unwrap();
+        assert_eq!(logged.iter().map(|entry| entry.bytes.as_slice()).collect::<Vec<_>>(), vec![b"ab", b"cd"]);
+        assert_eq!(journal.sync_count(), 0);
+
+        journal.sync().unwrap();
+        assert_eq!(journal.sync_count(), 1);
+        assert!(Wal::new(log.cursor()).committed().unwrap().is_empty());
+
+        // without syncing on commit, the log is cleared right after the blocks are written
+        journal.set_sync_policy(SyncPolicy::Never);
+        journal.begin().unwrap();
+        journal.write_all(b"ef").unwrap();
+        journal.commit().unwrap();
+        assert!(Wal::new(log.cursor()).committed().unwrap().is_empty());
+        assert_eq!(target.to_vec().unwrap(), b"abcdef");
+        assert_eq!(journal.sync_count(), 1);
+    }
+
+    #[test]
+    fn test_direct_write_survives_replay() {
+        let target = MemoryBuffer::new();
+        let log = MemoryBuffer::new();
+        let mut journal = Journal::new(Box::new(target.cursor()), Some(Wal::new(log.cursor())));
+
+        journal.begin().unwrap();
+        journal.write_all(b"ab").unwrap();
+        journal.commit().unwrap();
+        journal.seek(SeekFrom::Start(0)).unwrap();
+        journal.write_all(b"x").unwrap();
+        assert_eq!(journal.sync_count(), 1);
+
+        // the process dies without syncing, opening the file again replays the log
+        drop(journal);
+        let mut cursor = target.cursor();
+        Wal::new(log.cursor()).recover(&mut cursor).unwrap();
+        assert_eq!(target.to_vec().unwrap(), b"xb");
+    }
+}